@@ -1,12 +1,22 @@
 const fs = require("fs");
 async function main() {
+    const memory = new WebAssembly.Memory({
+        initial: 2,
+        maximum: 2
+    });
     const env = {
         putc: val => process.stdout.write(String.fromCharCode(val)),
-        getc: () => process.stdin.read(1).charCodeAt(0),
-        memory: new WebAssembly.Memory({
-            initial: 1,
-            maximum: 1
-        })
+        puts: (ptr, len) => process.stdout.write(Buffer.from(memory.buffer, ptr, len)),
+        getc: () => {
+            const buf = Buffer.alloc(1);
+            return fs.readSync(0, buf, 0, 1, null) === 1 ? buf[0] : -1;
+        },
+        read: (ptr, len) => fs.readSync(0, Buffer.from(memory.buffer, ptr, len), 0, len, null),
+        write: (fd, val) => fs.writeSync(fd, String.fromCharCode(val)),
+        dump: ptr => console.error("dump:", ptr, new Uint8Array(memory.buffer, Math.max(ptr - 8, 0), 16)),
+        trap: code => console.error("trap:", code),
+        high_water: cells => console.error("the program used", cells, "cells of the tape"),
+        memory: memory
     };
     const bytes = fs.readFileSync("target/bf.wasm");
     const module = await WebAssembly
@@ -0,0 +1,60 @@
+use crate::io_error;
+use bf2wasm::{CompileOptions, Error};
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+/// A short hash of `bytes`, the same way the cache names its modules, for
+/// telling outputs apart at a glance. It's only comparable between builds
+/// of bf2wasm by the same version of Rust.
+pub fn short_hash(bytes: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// A directory of compiled modules, each named for a hash of its source and
+/// the options it was compiled with, so that a build system can recompile
+/// everything and only pay for what's changed.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: &Path) -> Cache {
+        Cache {
+            dir: dir.to_owned(),
+        }
+    }
+
+    /// Where the module for `bf` compiled with `options` goes.
+    ///
+    /// Every option can change the module, so the key covers all of them,
+    /// along with the version of bf2wasm which compiled it.
+    fn path(&self, bf: &[u8], options: &CompileOptions) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        env!("CARGO_PKG_VERSION").hash(&mut hasher);
+        format!("{:?}", options).hash(&mut hasher);
+        bf.hash(&mut hasher);
+        self.dir.join(format!("{:016x}.wasm", hasher.finish()))
+    }
+
+    /// The module for `bf` compiled with `options`, if it's in the cache. A
+    /// module which can't be read is as good as missing.
+    pub fn get(&self, bf: &[u8], options: &CompileOptions) -> Option<Vec<u8>> {
+        fs::read(self.path(bf, options)).ok()
+    }
+
+    /// Add the module for `bf` compiled with `options` to the cache.
+    pub fn put(&self, bf: &[u8], options: &CompileOptions, wasm: &[u8]) -> Result<(), Error> {
+        fs::create_dir_all(&self.dir).map_err(io_error)?;
+        // Write somewhere else first, so that a compile that's interrupted
+        // or racing another can't leave half a module in the cache.
+        let path = self.path(bf, options);
+        let partial = path.with_extension(format!("{}.partial", std::process::id()));
+        fs::write(&partial, wasm).map_err(io_error)?;
+        fs::rename(&partial, &path).map_err(io_error)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+use crate::position::bom_len;
+use crate::CompileOptions;
+use std::borrow::Cow;
+
+/// Blank out line comments, from each `;` up to the end of its line, if
+/// they're enabled.
+///
+/// Comments become spaces rather than being removed, so that offsets into
+/// the result are still offsets into the source.
+pub(crate) fn strip_comments<'a>(bf: &'a [u8], options: &CompileOptions) -> Cow<'a, [u8]> {
+    if !options.line_comments {
+        return Cow::Borrowed(bf);
+    }
+    let mut stripped = bf.to_vec();
+    let mut in_comment = false;
+    for byte in &mut stripped {
+        match *byte {
+            b';' => in_comment = true,
+            b'\n' => in_comment = false,
+            _ => {}
+        }
+        if in_comment {
+            *byte = b' ';
+        }
+    }
+    Cow::Owned(stripped)
+}
+
+const EXPECT_OUTPUT: &[u8] = b";; expect-output:";
+
+/// The output a source says it should produce, from its `;; expect-output:`
+/// comments, if it has any and line comments are enabled. Without them, the
+/// `-` in the directive would be a command.
+///
+/// The rest of each directive's line is expected output, less the space
+/// after the colon, with `\n`, `\t` and `\\` for a newline, a tab and a
+/// backslash. A source with several directives expects each one's output in
+/// turn.
+pub fn expected_output(bf: &[u8], options: &CompileOptions) -> Option<Vec<u8>> {
+    if !options.line_comments {
+        return None;
+    }
+    let mut expected = None;
+    for line in bf.split(|&byte| byte == b'\n') {
+        let start = match line
+            .windows(EXPECT_OUTPUT.len())
+            .position(|window| window == EXPECT_OUTPUT)
+        {
+            Some(start) => start + EXPECT_OUTPUT.len(),
+            None => continue,
+        };
+        let mut text = &line[start..];
+        if text.last() == Some(&b'\r') {
+            text = &text[..text.len() - 1];
+        }
+        if text.first() == Some(&b' ') {
+            text = &text[1..];
+        }
+        let expected = expected.get_or_insert_with(Vec::new);
+        let mut bytes = text.iter();
+        while let Some(&byte) = bytes.next() {
+            if byte != b'\\' {
+                expected.push(byte);
+                continue;
+            }
+            match bytes.next() {
+                Some(b'n') => expected.push(b'\n'),
+                Some(b't') => expected.push(b'\t'),
+                Some(&other) => expected.push(other),
+                None => expected.push(b'\\'),
+            }
+        }
+    }
+    expected
+}
+
+const OPTION_HEADER: &[u8] = b";; bf2wasm:";
+
+/// The command line options in the header a source can start with, a line
+/// like `;; bf2wasm: --cell-size 16`, if it has one, along with the source
+/// with the header blanked out, since its `-`s would be commands otherwise.
+///
+/// Blanking rather than removing the header keeps offsets and line numbers
+/// into the source the same.
+pub fn option_header(bf: &[u8]) -> Option<(Vec<String>, Vec<u8>)> {
+    let start = bom_len(bf);
+    if !bf[start..].starts_with(OPTION_HEADER) {
+        return None;
+    }
+    let end = bf[start..]
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .map_or(bf.len(), |len| start + len);
+    let args = String::from_utf8_lossy(&bf[start + OPTION_HEADER.len()..end])
+        .split_whitespace()
+        .map(String::from)
+        .collect();
+    let mut blanked = bf.to_vec();
+    for byte in &mut blanked[start..end] {
+        *byte = b' ';
+    }
+    Some((args, blanked))
+}
@@ -0,0 +1,506 @@
+use crate::position::Lines;
+use crate::reader::{Reader, CODE_SECTION, CUSTOM_SECTION, IMPORT_SECTION};
+use crate::{Error, ErrorKind};
+
+/// The name of the custom section mapping code to source.
+pub const DEBUG_SECTION: &str = "bf2wasm.debug";
+
+/// The custom section naming a module's functions, locals and labels.
+const NAME_SECTION: &str = "name";
+
+/// The id of the name section's subsection for labels, from the extended
+/// name section proposal.
+const LABEL_NAMES: u8 = 3;
+
+const BLOCK: u8 = 0x02;
+const LOOP: u8 = 0x03;
+const IF: u8 = 0x04;
+const I32_CONST: u8 = 0x41;
+const DROP: u8 = 0x1a;
+
+/// Skip the immediates of the instruction `opcode`, which has just been
+/// read.
+fn skip_immediates(reader: &mut Reader, opcode: u8) -> Result<(), Error> {
+    match opcode {
+        // Block types are a single byte in the MVP, but a type index with
+        // multiple values.
+        0x02..=0x04 => {
+            reader.sleb()?;
+        }
+        0x0c | 0x0d | 0x10 | 0x20..=0x24 => {
+            reader.uleb()?;
+        }
+        0x0e => {
+            let len = reader.len()?;
+            for _ in 0..=len {
+                reader.uleb()?;
+            }
+        }
+        0x11 => {
+            reader.uleb()?;
+            reader.skip(1)?;
+        }
+        0x28..=0x3e => {
+            reader.uleb()?;
+            reader.uleb()?;
+        }
+        0x3f | 0x40 => reader.skip(1)?,
+        0x41 | 0x42 => {
+            reader.sleb()?;
+        }
+        0x43 => reader.skip(4)?,
+        0x44 => reader.skip(8)?,
+        0x00 | 0x01 | 0x05 | 0x0b | 0x0f | 0x1a | 0x1b | 0x45..=0xc4 => {}
+        0xfc => match reader.uleb()? {
+            0..=7 => {}
+            8 => {
+                reader.uleb()?;
+                reader.skip(1)?;
+            }
+            9 => {
+                reader.uleb()?;
+            }
+            10 => reader.skip(2)?,
+            11 => reader.skip(1)?,
+            _ => Err(ErrorKind::Ice)?,
+        },
+        _ => Err(ErrorKind::Ice)?,
+    }
+    Ok(())
+}
+
+/// Find every marker in one function body, which runs from the reader's
+/// position to `end`, as pairs of the marker's offset and its index.
+fn find_markers(
+    reader: &mut Reader,
+    end: usize,
+    markers: &mut Vec<(usize, usize)>,
+) -> Result<(), Error> {
+    let groups = reader.len()?;
+    for _ in 0..groups {
+        reader.uleb()?;
+        reader.skip(1)?;
+    }
+    while reader.position < end {
+        let start = reader.position;
+        let opcode = reader.byte()?;
+        if opcode == I32_CONST {
+            let index = reader.sleb()?;
+            if reader.wasm.get(reader.position) == Some(&DROP) {
+                markers.push((start, index as usize));
+            }
+        } else {
+            skip_immediates(reader, opcode)?;
+        }
+    }
+    if reader.position != end {
+        Err(ErrorKind::Ice)?;
+    }
+    Ok(())
+}
+
+/// Find the loops `build_loop` built in one function body, which runs from
+/// the reader's position to `end`, as pairs of the label index of the
+/// `block` a loop breaks out of and the index of the `Open`'s marker. The
+/// `loop` itself is the next label.
+///
+/// Nothing else puts a `loop` straight inside a `block`, and the loop's
+/// code is the first thing after its `Open`'s marker to do so.
+fn find_loops(
+    reader: &mut Reader,
+    end: usize,
+    loops: &mut Vec<(usize, usize)>,
+) -> Result<(), Error> {
+    let groups = reader.len()?;
+    for _ in 0..groups {
+        reader.uleb()?;
+        reader.skip(1)?;
+    }
+    let mut labels = 0;
+    let mut marker = None;
+    let mut open_block = None;
+    while reader.position < end {
+        let opcode = reader.byte()?;
+        let after_block = open_block.take();
+        match opcode {
+            I32_CONST => {
+                let index = reader.sleb()?;
+                if reader.wasm.get(reader.position) == Some(&DROP) {
+                    marker = Some(index as usize);
+                }
+            }
+            BLOCK | LOOP | IF => {
+                reader.sleb()?;
+                if opcode == BLOCK {
+                    open_block = Some(labels);
+                } else if opcode == LOOP {
+                    if let (Some(block), Some(marker)) = (after_block, marker.take()) {
+                        loops.push((block, marker));
+                    }
+                }
+                labels += 1;
+            }
+            _ => skip_immediates(reader, opcode)?,
+        }
+    }
+    if reader.position != end {
+        Err(ErrorKind::Ice)?;
+    }
+    Ok(())
+}
+
+/// Skip the limits of a table or memory.
+fn skip_limits(reader: &mut Reader) -> Result<(), Error> {
+    let flags = reader.byte()?;
+    reader.uleb()?;
+    if flags & 1 != 0 {
+        reader.uleb()?;
+    }
+    Ok(())
+}
+
+/// The number of functions `wasm` imports, which come before its own in
+/// the function index space.
+fn imported_functions(wasm: &[u8]) -> Result<usize, Error> {
+    let mut reader = Reader::new(wasm);
+    while reader.position < wasm.len() {
+        let id = reader.byte()?;
+        let len = reader.len()?;
+        let end = reader.position + len;
+        if id == IMPORT_SECTION {
+            let mut functions = 0;
+            for _ in 0..reader.len()? {
+                for _ in 0..2 {
+                    let len = reader.len()?;
+                    reader.skip(len)?;
+                }
+                match reader.byte()? {
+                    0x00 => {
+                        reader.uleb()?;
+                        functions += 1;
+                    }
+                    0x01 => {
+                        reader.skip(1)?;
+                        skip_limits(&mut reader)?;
+                    }
+                    0x02 => skip_limits(&mut reader)?,
+                    0x03 => reader.skip(2)?,
+                    _ => Err(ErrorKind::Ice)?,
+                }
+            }
+            return Ok(functions);
+        }
+        reader.position = end;
+    }
+    Ok(0)
+}
+
+fn push_uleb(bytes: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// The markers in every function body in `wasm`, as pairs of the marker's
+/// offset and its index, along with the offset and length of the code
+/// section's contents.
+fn scan_markers(wasm: &[u8]) -> Result<(Vec<(usize, usize)>, usize, usize), Error> {
+    let mut markers = Vec::new();
+    let mut code = (0, 0);
+    let mut reader = Reader::new(wasm);
+    while reader.position < wasm.len() {
+        let id = reader.byte()?;
+        let len = reader.len()?;
+        let end = reader.position + len;
+        if id == CODE_SECTION {
+            code = (reader.position, len);
+            let bodies = reader.len()?;
+            for _ in 0..bodies {
+                let len = reader.len()?;
+                let end = reader.position + len;
+                find_markers(&mut reader, end, &mut markers)?;
+            }
+        }
+        reader.position = end;
+    }
+    Ok((markers, code.0, code.1))
+}
+
+fn push_name(bytes: &mut Vec<u8>, name: &str) {
+    push_uleb(bytes, name.len());
+    bytes.extend_from_slice(name.as_bytes());
+}
+
+pub(crate) fn push_custom_section(wasm: &mut Vec<u8>, name: &str, contents: &[u8]) {
+    let mut payload = Vec::new();
+    push_name(&mut payload, name);
+    payload.extend_from_slice(contents);
+    wasm.push(CUSTOM_SECTION);
+    push_uleb(wasm, payload.len());
+    wasm.extend(payload);
+}
+
+/// Append a custom section to `wasm` mapping the code for each op to the
+/// source it came from.
+///
+/// walrus has no way to tell us where instructions end up in the encoded
+/// module, so `build` marks the start of each op's code with an
+/// `i32.const` of an index into `sources` followed by a `drop`, which we
+/// find again here. Custom sections can go anywhere, so adding one at the
+/// end doesn't move the code it describes.
+///
+/// The section is a count followed by that many pairs of the byte offset
+/// of a marker in the module and the byte offset of the source it came
+/// from, all as unsigned LEB128. The pairs are in the order they appear in
+/// the module.
+pub(crate) fn append_debug_info(wasm: &mut Vec<u8>, sources: &[usize]) -> Result<(), Error> {
+    let (markers, _, _) = scan_markers(wasm)?;
+    let mut contents = Vec::new();
+    push_uleb(&mut contents, markers.len());
+    for (offset, index) in markers {
+        let source = *sources.get(index).ok_or(ErrorKind::Ice)?;
+        push_uleb(&mut contents, offset);
+        push_uleb(&mut contents, source);
+    }
+    push_custom_section(wasm, DEBUG_SECTION, &contents);
+    Ok(())
+}
+
+/// Name the labels of every loop after where its `[` is in the source, as
+/// `loop@3:12` for the loop itself and `break@3:12` for the block around it,
+/// in the name section, so that disassemblies and debuggers can show them.
+///
+/// Label names are from the extended name section proposal. walrus only
+/// names functions and locals, so any name section it emitted is replaced
+/// with one that has the labels too, at the end of the module where it
+/// can't move the code.
+pub(crate) fn name_loops(
+    wasm: &mut Vec<u8>,
+    sources: &[usize],
+    lines: &Lines,
+) -> Result<(), Error> {
+    let imports = imported_functions(wasm)?;
+    let mut functions = Vec::new();
+    let mut name_section = None;
+    let mut reader = Reader::new(wasm);
+    while reader.position < wasm.len() {
+        let start = reader.position;
+        let id = reader.byte()?;
+        let len = reader.len()?;
+        let end = reader.position + len;
+        if id == CODE_SECTION {
+            for index in 0..reader.len()? {
+                let len = reader.len()?;
+                let body_end = reader.position + len;
+                let mut loops = Vec::new();
+                find_loops(&mut reader, body_end, &mut loops)?;
+                if !loops.is_empty() {
+                    functions.push((imports + index, loops));
+                }
+            }
+        } else if id == CUSTOM_SECTION {
+            let name_len = reader.len()?;
+            let name_end = reader.position + name_len;
+            if wasm.get(reader.position..name_end) == Some(NAME_SECTION.as_bytes()) {
+                name_section = Some((start, name_end, end));
+            }
+        }
+        reader.position = end;
+    }
+
+    let mut labels = Vec::new();
+    push_uleb(&mut labels, functions.len());
+    for (function, loops) in functions {
+        push_uleb(&mut labels, function);
+        push_uleb(&mut labels, loops.len() * 2);
+        for (block, marker) in loops {
+            let source = *sources.get(marker).ok_or(ErrorKind::Ice)?;
+            let position = lines.locate(source);
+            for &(label, kind) in &[(block, "break"), (block + 1, "loop")] {
+                push_uleb(&mut labels, label);
+                let name = format!("{}@{}:{}", kind, position.line, position.column);
+                push_name(&mut labels, &name);
+            }
+        }
+    }
+
+    // Subsections have to be in order of their ids.
+    let mut contents = Vec::new();
+    let mut labels = Some(labels);
+    let mut push_labels = |contents: &mut Vec<u8>| {
+        if let Some(labels) = labels.take() {
+            contents.push(LABEL_NAMES);
+            push_uleb(contents, labels.len());
+            contents.extend(labels);
+        }
+    };
+    if let Some((start, subsections, end)) = name_section {
+        let mut reader = Reader {
+            wasm: &wasm[..],
+            position: subsections,
+        };
+        while reader.position < end {
+            let subsection = reader.position;
+            let id = reader.byte()?;
+            let len = reader.len()?;
+            let subsection_end = reader.position + len;
+            if id >= LABEL_NAMES {
+                push_labels(&mut contents);
+            }
+            if id != LABEL_NAMES {
+                contents.extend_from_slice(&wasm[subsection..subsection_end]);
+            }
+            reader.position = subsection_end;
+        }
+        wasm.drain(start..end);
+    }
+    push_labels(&mut contents);
+    push_custom_section(wasm, NAME_SECTION, &contents);
+    Ok(())
+}
+
+const DW_TAG_COMPILE_UNIT: usize = 0x11;
+const DW_AT_NAME: usize = 0x03;
+const DW_AT_STMT_LIST: usize = 0x10;
+const DW_AT_LOW_PC: usize = 0x11;
+const DW_AT_HIGH_PC: usize = 0x12;
+const DW_AT_PRODUCER: usize = 0x25;
+const DW_FORM_ADDR: usize = 0x01;
+const DW_FORM_DATA4: usize = 0x06;
+const DW_FORM_STRING: usize = 0x08;
+const DW_FORM_SEC_OFFSET: usize = 0x17;
+const DW_LNS_COPY: u8 = 0x01;
+const DW_LNS_ADVANCE_LINE: u8 = 0x03;
+const DW_LNS_SET_COLUMN: u8 = 0x05;
+const DW_LNE_END_SEQUENCE: u8 = 0x01;
+const DW_LNE_SET_ADDRESS: u8 = 0x02;
+/// The number of operands of each standard line program opcode in DWARF 4.
+const STANDARD_OPCODE_LENGTHS: [u8; 12] = [0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+
+fn push_sleb(bytes: &mut Vec<u8>, mut value: i64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        let done = (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0);
+        if done {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+fn push_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_string(bytes: &mut Vec<u8>, s: &str) {
+    bytes.extend_from_slice(s.as_bytes());
+    bytes.push(0);
+}
+
+/// Fill in the length of a DWARF unit which started with a placeholder at
+/// `start`.
+fn patch_unit_length(bytes: &mut Vec<u8>, start: usize) {
+    let len = (bytes.len() - start - 4) as u32;
+    bytes[start..start + 4].copy_from_slice(&len.to_le_bytes());
+}
+
+fn push_set_address(program: &mut Vec<u8>, address: u32) {
+    program.push(0);
+    push_uleb(program, 5);
+    program.push(DW_LNE_SET_ADDRESS);
+    push_u32(program, address);
+}
+
+/// Append DWARF sections to `wasm` describing a single compile unit for the
+/// source, with a line table mapping each marker left by `build` to the
+/// line and column of the source it came from.
+///
+/// As for WebAssembly DWARF in general, addresses are offsets into the
+/// contents of the code section.
+pub(crate) fn append_dwarf(
+    wasm: &mut Vec<u8>,
+    sources: &[usize],
+    lines: &Lines,
+    source_name: &str,
+) -> Result<(), Error> {
+    let (markers, code_start, code_len) = scan_markers(wasm)?;
+
+    let mut abbrev = Vec::new();
+    push_uleb(&mut abbrev, 1);
+    push_uleb(&mut abbrev, DW_TAG_COMPILE_UNIT);
+    abbrev.push(0);
+    for &(attribute, form) in &[
+        (DW_AT_PRODUCER, DW_FORM_STRING),
+        (DW_AT_NAME, DW_FORM_STRING),
+        (DW_AT_STMT_LIST, DW_FORM_SEC_OFFSET),
+        (DW_AT_LOW_PC, DW_FORM_ADDR),
+        (DW_AT_HIGH_PC, DW_FORM_DATA4),
+    ] {
+        push_uleb(&mut abbrev, attribute);
+        push_uleb(&mut abbrev, form);
+    }
+    abbrev.extend_from_slice(&[0, 0, 0]);
+
+    let mut info = Vec::new();
+    push_u32(&mut info, 0);
+    push_u16(&mut info, 4);
+    push_u32(&mut info, 0);
+    info.push(4);
+    push_uleb(&mut info, 1);
+    push_string(&mut info, concat!("bf2wasm ", env!("CARGO_PKG_VERSION")));
+    push_string(&mut info, source_name);
+    push_u32(&mut info, 0);
+    push_u32(&mut info, 0);
+    push_u32(&mut info, code_len as u32);
+    patch_unit_length(&mut info, 0);
+
+    let mut line = Vec::new();
+    push_u32(&mut line, 0);
+    push_u16(&mut line, 4);
+    let header_length_at = line.len();
+    push_u32(&mut line, 0);
+    // Minimum instruction length, maximum operations per instruction,
+    // default is_stmt, line base, line range and opcode base.
+    line.extend_from_slice(&[1, 1, 1, -5i8 as u8, 14, 13]);
+    line.extend_from_slice(&STANDARD_OPCODE_LENGTHS);
+    // No include directories, then a single file, in the compilation
+    // directory, with no known time or size.
+    line.push(0);
+    push_string(&mut line, source_name);
+    line.extend_from_slice(&[0, 0, 0, 0]);
+    let header_length = (line.len() - header_length_at - 4) as u32;
+    line[header_length_at..header_length_at + 4].copy_from_slice(&header_length.to_le_bytes());
+
+    let mut previous_line = 1;
+    for (offset, index) in markers {
+        let source = *sources.get(index).ok_or(ErrorKind::Ice)?;
+        let position = lines.locate(source);
+        push_set_address(&mut line, (offset - code_start) as u32);
+        line.push(DW_LNS_ADVANCE_LINE);
+        push_sleb(&mut line, position.line as i64 - previous_line as i64);
+        previous_line = position.line;
+        line.push(DW_LNS_SET_COLUMN);
+        push_uleb(&mut line, position.column);
+        line.push(DW_LNS_COPY);
+    }
+    push_set_address(&mut line, code_len as u32);
+    line.extend_from_slice(&[0, 1, DW_LNE_END_SEQUENCE]);
+    patch_unit_length(&mut line, 0);
+
+    push_custom_section(wasm, ".debug_info", &info);
+    push_custom_section(wasm, ".debug_abbrev", &abbrev);
+    push_custom_section(wasm, ".debug_line", &line);
+    Ok(())
+}
@@ -0,0 +1,329 @@
+use crate::comments::strip_comments;
+use crate::json::json_string;
+use crate::position::BOM;
+use crate::{commands, ir, lint, ook, CompileOptions, Dialect, Extension, Position, Stats};
+use std::error::Error as StdError;
+use std::fmt::{self, Display};
+
+/// What's wrong with a piece of Brainfuck source.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum DiagnosticKind {
+    /// A `[` with no matching `]`.
+    UnmatchedOpen,
+
+    /// A `]` with no matching `[`.
+    UnmatchedClose,
+
+    /// A byte which isn't a Brainfuck command, in strict mode.
+    InvalidCharacter(u8),
+
+    /// An Ook! token left over at the end of the source, without a second
+    /// token to make a command.
+    DanglingOok,
+
+    /// A pair of Ook! tokens which doesn't make a command; only `Ook? Ook?`
+    /// doesn't.
+    InvalidOokCommand,
+
+    /// A `[` nested deeper than `CompileOptions::max_nesting` allows, which
+    /// is given.
+    TooDeep(u32),
+
+    /// A loop which never changes the cell it tests, so it can never end
+    /// once it starts. This is only a warning.
+    InfiniteLoop,
+}
+
+impl DiagnosticKind {
+    /// Whether this is only a warning, which doesn't stop the program
+    /// compiling unless warnings are denied.
+    pub fn is_warning(self) -> bool {
+        self == DiagnosticKind::InfiniteLoop
+    }
+}
+
+/// A problem found in Brainfuck source, and where it was found.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub position: Position,
+}
+
+impl Display for DiagnosticKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DiagnosticKind::UnmatchedOpen => write!(f, "unmatched '['"),
+            DiagnosticKind::UnmatchedClose => write!(f, "unmatched ']'"),
+            DiagnosticKind::InvalidCharacter(byte) => {
+                write!(f, "invalid character '{}'", (byte as char).escape_default())
+            }
+            DiagnosticKind::DanglingOok => write!(f, "Ook! token with no second half"),
+            DiagnosticKind::InvalidOokCommand => write!(f, "'Ook? Ook?' isn't a command"),
+            DiagnosticKind::TooDeep(max) => write!(f, "loops nested more than {} deep", max),
+            DiagnosticKind::InfiniteLoop => {
+                write!(f, "loop never changes its cell, so it can never end")
+            }
+        }
+    }
+}
+
+impl Diagnostic {
+    /// Describe the diagnostic as a single line of JSON, for tools to read.
+    ///
+    /// The span is the byte offsets of the start and end of the offending
+    /// source, and the line and column are those of its start.
+    pub fn to_json(&self) -> String {
+        let severity = if self.kind.is_warning() {
+            "warning"
+        } else {
+            "error"
+        };
+        format!(
+            "{{\"severity\":\"{}\",\"message\":{},\"span\":{{\"start\":{},\"end\":{}}},\"line\":{},\"column\":{}}}",
+            severity,
+            json_string(&self.kind.to_string()),
+            self.position.offset,
+            self.position.offset + 1,
+            self.position.line,
+            self.position.column
+        )
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.kind.is_warning() {
+            write!(f, "warning: ")?;
+        }
+        write!(f, "{} at {}", self.kind, self.position)
+    }
+}
+
+/// The cause of an `ErrorKind::InvalidInput` error from `validate`.
+#[derive(Debug)]
+pub(crate) struct Diagnostics(pub Vec<Diagnostic>);
+
+impl StdError for Diagnostics {}
+
+impl Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, diagnostic) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            Display::fmt(diagnostic, f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check Brainfuck source for mismatched brackets, loops nested too deep
+/// and, in strict mode, invalid characters, returning every problem found
+/// in source order.
+///
+/// Ook! source is checked for tokens which don't make commands too. Line
+/// comments, if they're enabled, are never checked.
+pub fn validate(bf: &[u8], options: &CompileOptions) -> Vec<Diagnostic> {
+    match options.dialect {
+        Dialect::Brainfuck => validate_commands(bf, options, options.strict),
+        Dialect::Ook => {
+            // The translation is nothing but commands and whitespace.
+            let (bf, mut diagnostics) =
+                ook::translate(&strip_comments(bf, options), options.strict);
+            diagnostics.extend(validate_commands(&bf, options, false));
+            diagnostics.sort_by_key(|diagnostic| diagnostic.position.offset);
+            diagnostics
+        }
+    }
+}
+
+/// Look for likely mistakes in Brainfuck source which are still valid, such
+/// as loops which can never end, returning a warning for each in source
+/// order. Sources which don't pass `validate` get none.
+pub fn warnings(bf: &[u8], options: &CompileOptions) -> Vec<Diagnostic> {
+    if !validate(bf, options).is_empty() {
+        return Vec::new();
+    }
+    let commands = commands(bf, options);
+    let instrs = ir::parse(&commands, options, &mut Stats::default());
+    lint::infinite_loops(&instrs)
+        .into_iter()
+        .map(|offset| Diagnostic {
+            kind: DiagnosticKind::InfiniteLoop,
+            position: Position::locate(bf, offset),
+        })
+        .collect()
+}
+
+fn validate_commands(bf: &[u8], options: &CompileOptions, strict: bool) -> Vec<Diagnostic> {
+    let mut validator = Validator::new(options, strict);
+    validator.feed(bf);
+    validator.finish()
+}
+
+/// Validates Brainfuck commands a piece at a time, for sources which don't
+/// fit in memory all at once.
+pub(crate) struct Validator {
+    debug: bool,
+    streams: bool,
+    max_nesting: u32,
+    /// Whether the source has been too deep yet, which is only reported the
+    /// first time.
+    too_deep: bool,
+    line_comments: bool,
+    strict: bool,
+    diagnostics: Vec<Diagnostic>,
+    open: Vec<Position>,
+    offset: usize,
+    line: usize,
+    line_start: usize,
+    in_comment: bool,
+    /// How much of a byte order mark the source has started with.
+    bom: usize,
+    /// A `\r` which is fine as long as a `\n` follows it.
+    carriage_return: Option<Position>,
+}
+
+impl Validator {
+    pub fn new(options: &CompileOptions, strict: bool) -> Validator {
+        Validator {
+            debug: options.has_extension(Extension::Debug),
+            streams: options.has_extension(Extension::Streams),
+            max_nesting: options.max_nesting,
+            too_deep: false,
+            line_comments: options.line_comments,
+            strict: strict,
+            diagnostics: Vec::new(),
+            open: Vec::new(),
+            offset: 0,
+            line: 1,
+            line_start: 0,
+            in_comment: false,
+            bom: 0,
+            carriage_return: None,
+        }
+    }
+
+    /// Check the next piece of the source.
+    ///
+    /// A byte order mark at the start and the `\r` of each `\r\n` aren't
+    /// commands, but they're not mistakes either, so strict mode allows them.
+    pub fn feed(&mut self, bf: &[u8]) {
+        for &byte in bf {
+            let offset = self.offset;
+            self.offset += 1;
+            if offset == self.bom && BOM.get(offset) == Some(&byte) {
+                self.bom += 1;
+                if self.bom == BOM.len() {
+                    self.line_start = BOM.len();
+                }
+                continue;
+            }
+            if offset == self.bom {
+                self.end_partial_bom();
+            }
+            if byte != b'\n' {
+                self.end_carriage_return();
+            }
+            self.carriage_return = None;
+            let position = Position {
+                offset: offset,
+                line: self.line,
+                column: offset - self.line_start + 1,
+            };
+            let diagnostics = &mut self.diagnostics;
+            let mut report = |kind| {
+                diagnostics.push(Diagnostic {
+                    kind: kind,
+                    position: position,
+                })
+            };
+            if self.in_comment && byte != b'\n' {
+                continue;
+            }
+            match byte {
+                b'+' | b'-' | b'>' | b'<' | b'.' | b',' => {}
+                b'#' if self.debug => {}
+                b'~' if self.streams => {}
+                b';' if self.line_comments => self.in_comment = true,
+                b'[' => {
+                    self.open.push(position);
+                    if self.max_nesting > 0
+                        && self.open.len() > self.max_nesting as usize
+                        && !self.too_deep
+                    {
+                        self.too_deep = true;
+                        report(DiagnosticKind::TooDeep(self.max_nesting));
+                    }
+                }
+                b']' => {
+                    if self.open.pop().is_none() {
+                        report(DiagnosticKind::UnmatchedClose);
+                    }
+                }
+                b'\r' => self.carriage_return = Some(position),
+                b'\n' => {
+                    self.in_comment = false;
+                    self.line += 1;
+                    self.line_start = offset + 1;
+                }
+                _ => {
+                    if self.strict {
+                        report(DiagnosticKind::InvalidCharacter(byte));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Report the start of a byte order mark which turned out not to be one,
+    /// in strict mode, now that the source has stopped following it.
+    fn end_partial_bom(&mut self) {
+        if self.bom < BOM.len() && self.strict {
+            self.diagnostics
+                .extend(
+                    BOM[..self.bom]
+                        .iter()
+                        .enumerate()
+                        .map(|(offset, &byte)| Diagnostic {
+                            kind: DiagnosticKind::InvalidCharacter(byte),
+                            position: Position {
+                                offset: offset,
+                                line: 1,
+                                column: offset + 1,
+                            },
+                        }),
+                );
+        }
+    }
+
+    /// Report a `\r` which wasn't followed by a `\n`, in strict mode, if
+    /// there is one.
+    fn end_carriage_return(&mut self) {
+        if let Some(position) = self.carriage_return {
+            if self.strict {
+                self.diagnostics.push(Diagnostic {
+                    kind: DiagnosticKind::InvalidCharacter(b'\r'),
+                    position: position,
+                });
+            }
+        }
+    }
+
+    /// Every problem found in the whole source, in source order.
+    pub fn finish(mut self) -> Vec<Diagnostic> {
+        if self.offset == self.bom {
+            self.end_partial_bom();
+        }
+        self.end_carriage_return();
+        self.diagnostics
+            .extend(self.open.into_iter().map(|position| Diagnostic {
+                kind: DiagnosticKind::UnmatchedOpen,
+                position: position,
+            }));
+
+        self.diagnostics
+            .sort_by_key(|diagnostic| diagnostic.position.offset);
+        self.diagnostics
+    }
+}
@@ -0,0 +1,138 @@
+use crate::diagnostic::Diagnostics;
+use crate::json;
+use crate::{Diagnostic, Position};
+use std::error::Error as StdError;
+use std::fmt::{self, Display};
+
+/// What went wrong, broadly.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ErrorKind {
+    Io,
+    InvalidInput,
+    Ice,
+    Runtime,
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            ErrorKind::Io => "I/O error",
+            ErrorKind::InvalidInput => "Invalid input",
+            ErrorKind::Ice => "Internal error; unable to generate WebAssembly",
+            ErrorKind::Runtime => "Error running WebAssembly",
+        })
+    }
+}
+
+impl StdError for ErrorKind {}
+
+/// The error for everything bf2wasm does. It displays as its kind, and where
+/// in the source the problem is, if it's about the source. The details, if
+/// there are any, are its `source`.
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    source: Option<Box<dyn StdError + Send + Sync>>,
+}
+
+impl Error {
+    /// An error of kind `kind`, caused by `source`, which can be another
+    /// error or just a message.
+    pub fn new<E>(kind: ErrorKind, source: E) -> Error
+    where
+        E: Into<Box<dyn StdError + Send + Sync>>,
+    {
+        Error {
+            kind: kind,
+            source: Some(source.into()),
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// The problems found in the source, if that's why compilation failed.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        self.source
+            .as_ref()
+            .and_then(|source| source.downcast_ref::<Diagnostics>())
+            .map_or(&[][..], |diagnostics| &diagnostics.0[..])
+    }
+
+    /// Where in the source the first problem found in it is, if that's why
+    /// compilation failed.
+    pub fn position(&self) -> Option<Position> {
+        self.diagnostics()
+            .first()
+            .map(|diagnostic| diagnostic.position)
+    }
+
+    /// The error and everything that caused it, separated by colons.
+    pub fn message(&self) -> String {
+        let mut message = self.to_string();
+        let mut source = self.source();
+        while let Some(cause) = source {
+            message += &format!(": {}", cause);
+            source = cause.source();
+        }
+        message
+    }
+
+    /// Describe the error as JSON, for tools to read: a line for each
+    /// diagnostic, if there are any, or else a single line with the whole
+    /// message.
+    pub fn to_json(&self) -> String {
+        let diagnostics = self.diagnostics();
+        if !diagnostics.is_empty() {
+            let lines: Vec<String> = diagnostics.iter().map(Diagnostic::to_json).collect();
+            return lines.join("\n");
+        }
+        format!(
+            "{{\"severity\":\"error\",\"message\":{}}}",
+            json::json_string(&self.message())
+        )
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Display::fmt(&self.kind, f)?;
+        if let Some(position) = self.position() {
+            write!(f, " at {}", position)?;
+        }
+        Ok(())
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match &self.source {
+            Some(source) => Some(&**source),
+            None => None,
+        }
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Error {
+        Error {
+            kind: kind,
+            source: None,
+        }
+    }
+}
+
+/// Turn the error in a result into an `Error` of some kind.
+pub(crate) trait ResultExt<T> {
+    fn context(self, kind: ErrorKind) -> Result<T, Error>;
+}
+
+impl<T, E> ResultExt<T> for Result<T, E>
+where
+    E: Into<Box<dyn StdError + Send + Sync>>,
+{
+    fn context(self, kind: ErrorKind) -> Result<T, Error> {
+        self.map_err(|error| Error::new(kind, error))
+    }
+}
@@ -0,0 +1,102 @@
+use crate::CompileOptions;
+
+const TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>bf2wasm</title>
+</head>
+<body>
+<p><textarea id="input" rows="8" cols="80" placeholder="Input"></textarea></p>
+<p><button id="run">Run</button></p>
+<p><textarea id="output" rows="24" cols="80" readonly></textarea></p>
+<script>
+document.getElementById("run").addEventListener("click", async () => {
+    const input = new TextEncoder().encode(document.getElementById("input").value);
+    const output = [];
+    let position = 0;
+    let memory = $MEMORY;
+    const env = {
+        [$PUTC]: val => output.push(val & 0xff),
+        puts: (ptr, len) => {
+            for (const byte of new Uint8Array(memory.buffer, ptr, len)) {
+                output.push(byte);
+            }
+        },
+        [$GETC]: () => position < input.length ? input[position++] : -1,
+        read: (ptr, len) => {
+            const bytes = input.subarray(position, position + len);
+            new Uint8Array(memory.buffer, ptr, len).set(bytes);
+            position += bytes.length;
+            return bytes.length;
+        },
+        write: (stream, val) => (stream === 1 ? output.push(val & 0xff) : console.log("stream", stream + ":", val & 0xff)),
+        dump: ptr => console.log("dump:", ptr, new Uint8Array(memory.buffer, ptr, 1)[0]),
+        trap: code => console.log("trap:", code),
+        high_water: cells => console.log("the program used", cells, "cells of the tape"),
+    };
+    if (memory) {
+        env[$FIELD_MEMORY] = memory;
+    }
+    const response = await fetch($WASM);
+    const bytes = await response.arrayBuffer();
+    const { instance } = await WebAssembly.instantiate(bytes, { [$IMPORT_MODULE]: env });
+    memory = memory || instance.exports.memory;
+$MAIN    document.getElementById("output").value =
+        new TextDecoder().decode(new Uint8Array(output));
+});
+</script>
+</body>
+</html>
+"#;
+
+/// Quote `s` as a JavaScript string literal that's also safe inside a
+/// `<script>` element.
+fn js_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '<' => quoted.push_str("\\x3c"),
+            // So nothing quoted can look like a placeholder in the template.
+            '$' => quoted.push_str("\\x24"),
+            '\n' => quoted.push_str("\\n"),
+            _ => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Generate an HTML page which fetches the module at `wasm_url`, compiled
+/// with `options`, and runs it against a pair of text areas.
+pub fn html_harness(wasm_url: &str, options: &CompileOptions) -> String {
+    let memory = if options.self_contained_memory {
+        String::from("undefined")
+    } else {
+        format!(
+            "new WebAssembly.Memory({{ initial: {} }})",
+            options.memory_pages().max(1)
+        )
+    };
+    // A start function has already run the program during instantiation.
+    let main = if options.start {
+        String::new()
+    } else {
+        format!(
+            "    instance.exports[{}]();\n",
+            js_string(&options.export_name)
+        )
+    };
+    TEMPLATE
+        .replace("$MEMORY", &memory)
+        .replace("$MAIN", &main)
+        .replace("$PUTC", &js_string(&options.putc_import))
+        .replace("$GETC", &js_string(&options.getc_import))
+        .replace("$FIELD_MEMORY", &js_string(&options.memory_import))
+        .replace("$IMPORT_MODULE", &js_string(&options.import_module))
+        .replace("$WASM", &js_string(wasm_url))
+}
@@ -0,0 +1,339 @@
+use crate::ir::{Instr, Op};
+use crate::{BfContext, Cell, CompileOptions, Cursor};
+use walrus::ir::{BinaryOp, BlockId, ExprId, ExtendedLoad, LoadKind, MemArg, UnaryOp};
+use walrus::{FunctionBuilder, LocalId};
+
+/// An op in the interpreter's bytecode. Each is a byte for the opcode and a
+/// little-endian `i32` operand, and `MulAdd` and `MulSet` have their factor
+/// in a second `i32` after that.
+///
+/// Offsets and steps are in bytes rather than cells, and jumps are to
+/// absolute addresses, so the interpreter doesn't have to work them out.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Opcode {
+    Add,
+    Move,
+    Output,
+    Input,
+    /// Jump to the operand if the current cell is zero.
+    Open,
+    /// Jump to the operand if the current cell is nonzero.
+    Close,
+    Set,
+    Scan,
+    MulAdd,
+    MulSet,
+    Dump,
+    Stream,
+}
+
+/// Every opcode, in the order of the dispatch table.
+const OPCODES: [Opcode; 12] = [
+    Opcode::Add,
+    Opcode::Move,
+    Opcode::Output,
+    Opcode::Input,
+    Opcode::Open,
+    Opcode::Close,
+    Opcode::Set,
+    Opcode::Scan,
+    Opcode::MulAdd,
+    Opcode::MulSet,
+    Opcode::Dump,
+    Opcode::Stream,
+];
+
+/// Ends the program. It's past the end of the dispatch table, so it leaves
+/// the interpreter loop.
+const END: u8 = Opcode::Stream as u8 + 1;
+
+/// The size in bytes of an op, not counting the factor of a `MulAdd` or
+/// `MulSet`.
+const OP_BYTES: usize = 5;
+
+/// Encode a program as bytecode to be loaded at `start` in memory.
+///
+/// Fills, batched output and precomputed output aren't worth having in
+/// bytecode, so they mustn't be in the program.
+pub(crate) fn encode(instrs: &[Instr], options: &CompileOptions, start: u32) -> Vec<u8> {
+    let cell_bytes = options.cell_size.bytes() as i32;
+    let address = |offset: usize| (start as usize + offset) as i32;
+    let mut bytecode = Vec::new();
+    let mut open_loops = Vec::new();
+    for instr in instrs {
+        let (opcode, operand) = match instr.op {
+            Op::Add(delta) => (Opcode::Add, delta),
+            Op::Move(offset) => (Opcode::Move, offset.wrapping_mul(cell_bytes)),
+            Op::Output => (Opcode::Output, 0),
+            Op::Input => (Opcode::Input, 0),
+            Op::Open => {
+                // The jump past the loop is filled in at its end.
+                open_loops.push(bytecode.len());
+                (Opcode::Open, 0)
+            }
+            Op::Close => {
+                let open = open_loops.pop().unwrap();
+                let after = address(bytecode.len() + OP_BYTES);
+                bytecode[open + 1..open + OP_BYTES].copy_from_slice(&after.to_le_bytes());
+                (Opcode::Close, address(open + OP_BYTES))
+            }
+            Op::Clear => (Opcode::Set, 0),
+            // Storing the value truncates it to the width of a cell.
+            Op::Set(value) => (Opcode::Set, value),
+            Op::Scan(step) => (Opcode::Scan, step.wrapping_mul(cell_bytes)),
+            Op::MulAdd { offset, .. } => (Opcode::MulAdd, offset.wrapping_mul(cell_bytes)),
+            Op::MulSet { offset, .. } => (Opcode::MulSet, offset.wrapping_mul(cell_bytes)),
+            Op::Dump => (Opcode::Dump, 0),
+            Op::Stream => (Opcode::Stream, 0),
+            op @ Op::Fill(_) | op @ Op::Stage(_) | op @ Op::Flush(_) | op @ Op::Print(_) => {
+                unreachable!("the interpreter has no {:?}", op)
+            }
+        };
+        bytecode.push(opcode as u8);
+        bytecode.extend_from_slice(&operand.to_le_bytes());
+        match instr.op {
+            Op::MulAdd { factor, .. } | Op::MulSet { factor, .. } => {
+                bytecode.extend_from_slice(&factor.to_le_bytes())
+            }
+            _ => {}
+        }
+    }
+    bytecode.push(END);
+    bytecode
+}
+
+/// Build the interpreter loop, which runs the bytecode at `start`, as the
+/// code for the top level of the program.
+///
+/// `pc` holds the address of the op being run, and `operand` its operand.
+pub(crate) fn build(
+    context: &BfContext,
+    builder: &mut FunctionBuilder,
+    pc: LocalId,
+    operand: LocalId,
+    start: i32,
+) -> Vec<ExprId> {
+    let start = builder.i32_const(start);
+    let set_pc = builder.local_set(pc, start);
+    let mut exit = builder.block(Box::new([]), Box::new([]));
+    let exit_id = exit.id();
+    let mut dispatch = exit.loop_(Box::new([]));
+    let dispatch_id = dispatch.id();
+    let interpreter = Interpreter {
+        context: context,
+        pc: pc,
+        operand: operand,
+        exit: exit_id,
+        dispatch: dispatch_id,
+    };
+    let last = OPCODES.len() - 1;
+    for expr in interpreter.handlers(&mut dispatch, last, &mut Vec::new()) {
+        dispatch.expr(expr);
+    }
+    drop(dispatch);
+    exit.expr(From::from(dispatch_id));
+    drop(exit);
+    vec![set_pc, From::from(exit_id)]
+}
+
+struct Interpreter<'a> {
+    context: &'a BfContext,
+    pc: LocalId,
+    operand: LocalId,
+
+    /// The block to break out of at the end of the program.
+    exit: BlockId,
+
+    /// The loop to continue with the next op.
+    dispatch: BlockId,
+}
+
+impl<'a> Interpreter<'a> {
+    /// Build the handlers for the opcodes up to `OPCODES[index]`, returning
+    /// the code for them.
+    ///
+    /// Each handler follows a block of its own, which the dispatch table
+    /// breaks out of to run it. The block for each opcode is nested inside
+    /// the next one's, with the dispatch itself innermost, so `targets`
+    /// collects the blocks from the outside in on the way down.
+    fn handlers(
+        &self,
+        builder: &mut FunctionBuilder,
+        index: usize,
+        targets: &mut Vec<BlockId>,
+    ) -> Vec<ExprId> {
+        let mut block = builder.block(Box::new([]), Box::new([]));
+        let block_id = block.id();
+        targets.push(block_id);
+        let body = if index == 0 {
+            let pc = block.local_get(self.pc);
+            let which = self.load_byte(&mut block, pc);
+            let table: Vec<BlockId> = targets.iter().rev().cloned().collect();
+            vec![block.br_table(which, table.into_boxed_slice(), self.exit, Box::new([]))]
+        } else {
+            self.handlers(&mut block, index - 1, targets)
+        };
+        for expr in body {
+            block.expr(expr);
+        }
+        drop(block);
+
+        let mut code = vec![From::from(block_id)];
+        self.handler(builder, &mut code, OPCODES[index]);
+        code.push(builder.br(self.dispatch, Box::new([])));
+        code
+    }
+
+    fn load_byte(&self, builder: &mut FunctionBuilder, address: ExprId) -> ExprId {
+        let arg = MemArg {
+            align: 1,
+            offset: 0,
+        };
+        let kind = LoadKind::I32_8 {
+            kind: ExtendedLoad::ZeroExtend,
+        };
+        builder.load(self.context.memory, kind, arg, address)
+    }
+
+    /// The `i32` `offset` bytes into the op being run.
+    fn load_word(&self, builder: &mut FunctionBuilder, offset: u32) -> ExprId {
+        let pc = builder.local_get(self.pc);
+        let arg = MemArg {
+            align: 1,
+            offset: offset,
+        };
+        builder.load(
+            self.context.memory,
+            LoadKind::I32 { atomic: false },
+            arg,
+            pc,
+        )
+    }
+
+    /// Build the code to run a single op. It starts with `pc` pointing at
+    /// the op, and leaves it pointing at the next one to run.
+    fn handler(&self, builder: &mut FunctionBuilder, code: &mut Vec<ExprId>, opcode: Opcode) {
+        let context = self.context;
+        let operand = self.load_word(builder, 1);
+        code.push(builder.local_set(self.operand, operand));
+        let factor = match opcode {
+            Opcode::MulAdd | Opcode::MulSet => {
+                let factor = self.load_word(builder, OP_BYTES as u32);
+                Some(context.spill(builder, code, factor))
+            }
+            _ => None,
+        };
+        let len = OP_BYTES as i32 + if factor.is_some() { 4 } else { 0 };
+        let pc = builder.local_get(self.pc);
+        let len = builder.i32_const(len);
+        let next = builder.binop(BinaryOp::I32Add, pc, len);
+        code.push(builder.local_set(self.pc, next));
+
+        let operand = builder.local_get(self.operand);
+        let mut cursor = Cursor {
+            shift: 0,
+            cell: Cell::Memory,
+        };
+        match opcode {
+            Opcode::Add => context.add_value(builder, code, &mut cursor, operand),
+            Opcode::Move => {
+                let p = builder.binop(BinaryOp::I32Add, context.p, operand);
+                code.push(builder.local_set(context.pointer, p));
+                context.check_bounds(builder, code, context.p);
+            }
+            Opcode::Output => {
+                let value = context.check_ascii(builder, code, context.at_p);
+                context.putc(builder, code, value);
+            }
+            Opcode::Input => context.input(builder, code, 0),
+            Opcode::Open => {
+                let mut skip = builder.block(Box::new([]), Box::new([]));
+                let enter = skip.id();
+                let nonzero = skip.br_if(context.at_p, enter, Box::new([]));
+                skip.expr(nonzero);
+                let jump = skip.local_set(self.pc, operand);
+                skip.expr(jump);
+                let next = skip.br(self.dispatch, Box::new([]));
+                skip.expr(next);
+                drop(skip);
+                code.push(From::from(enter));
+                context.count_step(builder, code);
+            }
+            Opcode::Close => {
+                let mut repeat = builder.block(Box::new([]), Box::new([]));
+                let done = repeat.id();
+                let zero = repeat.unop(UnaryOp::I32Eqz, context.at_p);
+                let exit = repeat.br_if(zero, done, Box::new([]));
+                repeat.expr(exit);
+                let mut step = Vec::new();
+                context.count_step(&mut repeat, &mut step);
+                step.push(repeat.local_set(self.pc, operand));
+                for expr in step {
+                    repeat.expr(expr);
+                }
+                drop(repeat);
+                code.push(From::from(done));
+            }
+            Opcode::Set => code.push(context.store_at(builder, 0, operand)),
+            Opcode::Scan => {
+                // As in `BfContext::scan`, step back first to test at the
+                // bottom of the loop.
+                let back = builder.binop(BinaryOp::I32Sub, context.p, operand);
+                code.push(builder.local_set(context.pointer, back));
+                let mut scan = builder.loop_(Box::new([]));
+                let scan_id = scan.id();
+                let mut body = Vec::new();
+                context.count_step(&mut scan, &mut body);
+                let forward = scan.binop(BinaryOp::I32Add, context.p, operand);
+                body.push(scan.local_set(context.pointer, forward));
+                context.check_bounds(&mut scan, &mut body, context.p);
+                body.push(scan.br_if(context.at_p, scan_id, Box::new([])));
+                for expr in body {
+                    scan.expr(expr);
+                }
+                drop(scan);
+                code.push(From::from(scan_id));
+            }
+            Opcode::MulAdd | Opcode::MulSet => {
+                // As in `BfContext::multiply_into`, an add leaves its target
+                // alone, unchecked, when the counter is zero.
+                let mut guard = builder.block(Box::new([]), Box::new([]));
+                let skip = guard.id();
+                let mut body = Vec::new();
+                if opcode == Opcode::MulAdd {
+                    let is_zero = guard.unop(UnaryOp::I32Eqz, context.at_p);
+                    body.push(guard.br_if(is_zero, skip, Box::new([])));
+                }
+                let address = guard.binop(BinaryOp::I32Add, context.p, operand);
+                context.check_bounds(&mut guard, &mut body, address);
+                let factor = factor.expect("multiplies have a factor");
+                let mut value = guard.binop(BinaryOp::I32Mul, context.at_p, factor);
+                if opcode == Opcode::MulAdd {
+                    let target =
+                        guard.load(context.memory, context.cell_load, context.cell_arg, address);
+                    value = guard.binop(BinaryOp::I32Add, target, value);
+                }
+                body.push(guard.store(
+                    context.memory,
+                    context.cell_store,
+                    context.cell_arg,
+                    address,
+                    value,
+                ));
+                for expr in body {
+                    guard.expr(expr);
+                }
+                drop(guard);
+                code.push(From::from(skip));
+            }
+            Opcode::Dump => {
+                let dump = context.dump.expect("# needs dump");
+                code.push(builder.call(dump, Box::new([context.p])));
+            }
+            Opcode::Stream => {
+                let stream = context.stream.expect("~ needs a stream");
+                code.push(builder.local_set(stream, context.at_p));
+            }
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use crate::{CompileOptions, Extension, Overflow, Stats};
+
+/// A single operation in a parsed Brainfuck program.
+///
+/// Loops are kept flat, as a matching `Open` and `Close`, so that neither
+/// building nor dropping a program recurses once per level of nesting.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub(crate) enum Op {
+    /// Add a constant to the current cell.
+    Add(i32),
+
+    /// Move the pointer by a constant number of cells.
+    Move(i32),
+
+    /// Pass the current cell to `putc`.
+    Output,
+
+    /// Store the result of `getc` in the current cell.
+    Input,
+
+    /// Begin a loop which runs while the current cell is nonzero.
+    Open,
+
+    /// End the innermost loop.
+    Close,
+
+    /// Set the current cell to zero.
+    Clear,
+
+    /// Set the current cell to a constant, truncated to the width of a cell.
+    Set(i32),
+
+    /// Clear the current cell and the cells next to it, this many in all,
+    /// towards higher addresses if it's positive and lower ones if it's
+    /// negative. The pointer stays put.
+    Fill(i32),
+
+    /// Move the pointer by a constant number of cells until the current cell
+    /// is zero, as `[>]` or `[<]` do.
+    Scan(i32),
+
+    /// Add the current cell, multiplied by `factor`, to the cell `offset`
+    /// cells away.
+    MulAdd { offset: i32, factor: i32 },
+
+    /// Set the cell `offset` cells away to the current cell multiplied by
+    /// `factor`.
+    MulSet { offset: i32, factor: i32 },
+
+    /// Copy the current cell into the given slot of the output batch.
+    Stage(u32),
+
+    /// Write the given number of staged bytes of output.
+    Flush(u32),
+
+    /// Pass the address of the current cell to `dump`.
+    Dump,
+
+    /// Write the output precomputed for the start of the program, which is
+    /// this many bytes.
+    Print(u32),
+
+    /// Make the current cell's value the stream `Output` writes to.
+    Stream,
+}
+
+/// An op, along with where it came from.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub(crate) struct Instr {
+    pub op: Op,
+
+    /// The byte offset in the source of the first command this op was built
+    /// from.
+    pub source: usize,
+}
+
+impl Instr {
+    pub fn new(op: Op, source: usize) -> Instr {
+        Instr {
+            op: op,
+            source: source,
+        }
+    }
+}
+
+fn push_add(instrs: &mut Vec<Instr>, delta: i32, source: usize, options: &CompileOptions) {
+    let overflow = options.overflow;
+    let last = if options.optimizations.fold_runs {
+        instrs.last_mut().map(|instr| &mut instr.op)
+    } else {
+        None
+    };
+    match last {
+        Some(Op::Add(n)) if overflow == Overflow::Wrap => *n = n.wrapping_add(delta),
+        // Without wrapping, `+-` at the limit of a cell isn't a no-op, so only
+        // steps in the same direction can be folded.
+        Some(Op::Add(n)) if n.signum() == delta.signum() => *n = n.saturating_add(delta),
+        _ => instrs.push(Instr::new(Op::Add(delta), source)),
+    }
+}
+
+fn push_move(instrs: &mut Vec<Instr>, offset: i32, source: usize, options: &CompileOptions) {
+    let last = if options.optimizations.fold_runs {
+        instrs.last_mut().map(|instr| &mut instr.op)
+    } else {
+        None
+    };
+    match last {
+        Some(Op::Move(n)) => *n = n.wrapping_add(offset),
+        _ => instrs.push(Instr::new(Op::Move(offset), source)),
+    }
+}
+
+/// Parse Brainfuck source, folding runs of `+`/`-` and `>`/`<` as we go
+/// unless that's been turned off.
+///
+/// When cells wrap, deltas are folded modulo 2^32, which agrees with the
+/// cell's own wrapping once a store truncates the result.
+///
+/// The source must already have passed `validate`. The commands in it are
+/// counted in `stats` along the way.
+pub(crate) fn parse(bf: &[u8], options: &CompileOptions, stats: &mut Stats) -> Vec<Instr> {
+    let mut parser = Parser::new(options);
+    parser.feed(bf, stats);
+    parser.finish()
+}
+
+/// Parses Brainfuck source a piece at a time, for sources which don't fit
+/// in memory all at once.
+pub(crate) struct Parser<'a> {
+    options: &'a CompileOptions,
+    debug: bool,
+    streams: bool,
+    instrs: Vec<Instr>,
+    offset: usize,
+    depth: usize,
+    in_comment: bool,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(options: &'a CompileOptions) -> Parser<'a> {
+        Parser {
+            options: options,
+            debug: options.has_extension(Extension::Debug),
+            streams: options.has_extension(Extension::Streams),
+            instrs: Vec::new(),
+            offset: 0,
+            depth: 0,
+            in_comment: false,
+        }
+    }
+
+    /// Parse the next piece of the source.
+    pub fn feed(&mut self, bf: &[u8], stats: &mut Stats) {
+        let options = self.options;
+        let instrs = &mut self.instrs;
+        for &byte in bf {
+            let source = self.offset;
+            self.offset += 1;
+            if self.in_comment {
+                self.in_comment = byte != b'\n';
+                continue;
+            }
+            match byte {
+                b'+' => stats.increments += 1,
+                b'-' => stats.decrements += 1,
+                b'>' => stats.rights += 1,
+                b'<' => stats.lefts += 1,
+                b'.' => stats.outputs += 1,
+                b',' => stats.inputs += 1,
+                b'[' => {
+                    stats.loops += 1;
+                    self.depth += 1;
+                    stats.max_depth = stats.max_depth.max(self.depth);
+                }
+                b']' => self.depth = self.depth.saturating_sub(1),
+                _ => {}
+            }
+            match byte {
+                b'+' => push_add(instrs, 1, source, options),
+                b'-' => push_add(instrs, -1, source, options),
+                b'>' => push_move(instrs, 1, source, options),
+                b'<' => push_move(instrs, -1, source, options),
+                b'.' => instrs.push(Instr::new(Op::Output, source)),
+                b',' => instrs.push(Instr::new(Op::Input, source)),
+                b'[' => instrs.push(Instr::new(Op::Open, source)),
+                b']' => instrs.push(Instr::new(Op::Close, source)),
+                b'#' if self.debug => instrs.push(Instr::new(Op::Dump, source)),
+                b'~' if self.streams => instrs.push(Instr::new(Op::Stream, source)),
+                b';' if options.line_comments => self.in_comment = true,
+                _ => {}
+            }
+        }
+    }
+
+    /// The ops parsed from the whole source.
+    pub fn finish(self) -> Vec<Instr> {
+        self.instrs
+    }
+}
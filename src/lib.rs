@@ -0,0 +1,2122 @@
+use std::borrow::Cow;
+use std::io::{self, Read};
+use std::mem;
+use std::panic;
+use walrus::ir::{BinaryOp, ExprId, ExtendedLoad, LoadKind, MemArg, StoreKind, UnaryOp, Value};
+use walrus::{
+    ActiveData, ActiveDataLocation, DataKind, ExportItem, FunctionBuilder, FunctionId, GlobalId,
+    ImportKind, InitExpr, LocalId, MemoryId, Module, ModuleConfig, TypeId, ValType,
+};
+
+mod comments;
+mod debug_info;
+mod diagnostic;
+mod error;
+mod html;
+mod interpreter;
+mod ir;
+mod json;
+mod lint;
+mod manifest;
+mod ook;
+mod optimize;
+mod options;
+mod padding;
+mod position;
+mod profile;
+mod reader;
+mod run;
+mod stats;
+mod trap;
+
+use crate::comments::strip_comments;
+use crate::diagnostic::{Diagnostics, Validator};
+use crate::error::ResultExt;
+use crate::ir::{Instr, Op};
+use crate::position::Lines;
+
+pub use crate::comments::{expected_output, option_header};
+pub use crate::debug_info::DEBUG_SECTION;
+pub use crate::diagnostic::{validate, warnings, Diagnostic, DiagnosticKind};
+pub use crate::error::{Error, ErrorKind};
+pub use crate::html::html_harness;
+pub use crate::manifest::manifest;
+pub use crate::options::{
+    Backend, CellSize, CompileOptions, Dialect, Eof, Extension, Feature, InputMode, Optimizations,
+    Overflow, Target, PAGE_SIZE,
+};
+pub use crate::padding::PADDING_SECTION;
+pub use crate::position::Position;
+pub use crate::profile::{Counter, PROFILE_EXPORT};
+pub use crate::run::{check_output, run, run_with_options, RunStats};
+pub use crate::stats::{Optimized, Stats};
+pub use crate::trap::TrapCode;
+pub use walrus;
+
+/// How the compiled program reaches the host for `.` and `,`.
+enum Io {
+    Env {
+        putc: FunctionId,
+        input: EnvInput,
+
+        /// Writes a batch of staged output, when output is batched, or the
+        /// precomputed output.
+        puts: Option<FunctionId>,
+
+        /// Writes a byte to a stream, which `.` uses instead of `putc` with
+        /// the streams extension.
+        write: Option<FunctionId>,
+    },
+
+    Wasi {
+        fd_write: FunctionId,
+        fd_read: FunctionId,
+    },
+}
+
+/// Where the `env` target's input comes from.
+enum EnvInput {
+    /// A byte at a time from `getc`.
+    Getc(FunctionId),
+
+    /// A buffer at a time from `read(ptr, len)`, into `IO_READ`.
+    Read(FunctionId),
+}
+
+// The layout of the page past the end of the tape which the WASI target,
+// batched output and buffered input keep their buffers in.
+
+/// A WASI iovec pointing at `IO_BYTE`, which `.` and `,` transfer through.
+const IO_IOVEC: i32 = 0;
+/// The byte count WASI functions return.
+const IO_NBYTES: i32 = 8;
+/// The byte transferred by `IO_IOVEC`.
+const IO_BYTE: i32 = 12;
+/// A WASI iovec pointing at `IO_BATCH`, for batched output.
+const IO_BATCH_IOVEC: i32 = 16;
+/// Output staged for a single write.
+const IO_BATCH: i32 = 32;
+/// Input read ahead by buffered input, in the second half of the page.
+const IO_READ: i32 = (PAGE_SIZE / 2) as i32;
+
+/// The number of bytes of output which can be staged before a write.
+pub(crate) const BATCH_CAPACITY: u32 = (IO_READ - IO_BATCH) as u32;
+
+/// The most input buffered input reads at once.
+const READ_CAPACITY: i32 = PAGE_SIZE as i32 - IO_READ;
+
+const WASI_STDIN: i32 = 0;
+const WASI_STDOUT: i32 = 1;
+
+/// Where the current cell's value lives at some point in straight-line code.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Cell {
+    /// Only in memory.
+    Memory,
+
+    /// In the `cell` local, and the same in memory.
+    Clean,
+
+    /// In the `cell` local, and not yet written back to memory.
+    Dirty,
+}
+
+/// What `build` is keeping track of for the code after the last op it built.
+#[derive(Copy, Clone, Debug)]
+struct Cursor {
+    /// How many cells past `pointer` the current cell is. Moves within
+    /// straight-line code only update `pointer` once the run ends, so cells
+    /// reached along the way are addressed by constant offsets from it.
+    shift: i32,
+
+    /// Where the current cell's value lives.
+    cell: Cell,
+}
+
+/// Whether `op` can work on the current cell while it's held in a local.
+fn caches_cell(op: Op) -> bool {
+    match op {
+        Op::Add(_)
+        | Op::Output
+        | Op::Stage(_)
+        | Op::Stream
+        | Op::MulAdd { .. }
+        | Op::MulSet { .. }
+        | Op::Clear
+        | Op::Set(_)
+        | Op::Flush(_) => true,
+        _ => false,
+    }
+}
+
+fn reads_cell(op: Op) -> bool {
+    match op {
+        Op::Add(_)
+        | Op::Output
+        | Op::Stage(_)
+        | Op::Stream
+        | Op::MulAdd { .. }
+        | Op::MulSet { .. } => true,
+        _ => false,
+    }
+}
+
+fn touches_cell(op: Op) -> bool {
+    match op {
+        Op::Clear | Op::Set(_) => true,
+        _ => reads_cell(op),
+    }
+}
+
+/// Whether `op` can work with a pointer that's behind by `Cursor::shift`.
+fn shifts_pointer(op: Op) -> bool {
+    match op {
+        Op::Move(_) | Op::Fill(_) => true,
+        _ => caches_cell(op),
+    }
+}
+
+struct BfContext {
+    memory: MemoryId,
+    io: Io,
+    io_page: i32,
+
+    /// The `env.dump` import, if `#` is enabled.
+    dump: Option<FunctionId>,
+
+    /// The local holding the stream `.` writes to, if `~` is enabled.
+    stream: Option<LocalId>,
+
+    /// The address of the iovec for the precomputed output, which the
+    /// output itself follows, if there is any.
+    precomputed: Option<i32>,
+
+    /// The pages to grow the memory to before the program runs, with
+    /// `grow_memory`.
+    grow_to: Option<u32>,
+
+    /// The `trap` import, if traps are reported.
+    trap: Option<FunctionId>,
+
+    /// The global counting down the loop iterations left, if there's a
+    /// limit.
+    steps: Option<GlobalId>,
+
+    /// When sanitizing, the global holding the address of the furthest cell
+    /// reached so far, and the `high_water` import to report it to.
+    high_water: Option<(GlobalId, FunctionId)>,
+
+    /// The global for each `Counter`, by index, when profiling.
+    counters: Option<Vec<GlobalId>>,
+
+    cell_bytes: i32,
+    cell_arg: MemArg,
+    cell_load: LoadKind,
+    cell_store: StoreKind,
+    cell_max: i32,
+    signed_cells: bool,
+    eof: Eof,
+    overflow: Overflow,
+    last_cell: Option<i32>,
+
+    /// The size of the tape in bytes, if the pointer wraps around it.
+    wrap_bytes: Option<u32>,
+    pointer: LocalId,
+    scratch: LocalId,
+
+    /// How far buffered input has got through the buffer, and how much is
+    /// in it.
+    read_position: LocalId,
+    read_len: LocalId,
+
+    /// Holds the current cell across a run of ops which work on it.
+    cell: LocalId,
+
+    /// With the tape in locals, the first cell any of them holds, relative
+    /// to where the pointer starts, and a local for each cell from there on.
+    /// The pointer never moves at runtime then: the current cell is always
+    /// `Cursor::shift` cells from the start.
+    tape_locals: Option<(i32, Vec<LocalId>)>,
+    cache_cells: bool,
+    defer_moves: bool,
+    eqz_loops: bool,
+    strict_ascii_output: bool,
+    zero: ExprId,
+    p: ExprId,
+    at_p: ExprId,
+}
+
+impl BfContext {
+    /// The address of the cell `offset` cells away from the pointer.
+    fn address(&self, builder: &mut FunctionBuilder, offset: i32) -> ExprId {
+        if let Some(tape_bytes) = self.wrap_bytes {
+            return self.wrapped_address(builder, offset, tape_bytes);
+        }
+        if offset == 0 {
+            self.p
+        } else {
+            let offset = builder.i32_const(offset.wrapping_mul(self.cell_bytes));
+            builder.binop(BinaryOp::I32Add, self.p, offset)
+        }
+    }
+
+    /// The address of the cell `offset` cells away from the pointer, on a
+    /// tape of `tape_bytes` bytes which wraps around at the ends.
+    fn wrapped_address(
+        &self,
+        builder: &mut FunctionBuilder,
+        offset: i32,
+        tape_bytes: u32,
+    ) -> ExprId {
+        // Going forwards by less than a lap instead keeps the sum from going
+        // negative, and below twice the size of the tape.
+        let cells = i64::from(tape_bytes) / i64::from(self.cell_bytes);
+        let offset = (i64::from(offset) % cells + cells) % cells;
+        if offset == 0 {
+            return self.p;
+        }
+        let bytes = builder.i32_const((offset * i64::from(self.cell_bytes)) as i32);
+        let sum = builder.binop(BinaryOp::I32Add, self.p, bytes);
+        if tape_bytes.is_power_of_two() {
+            let mask = builder.i32_const((tape_bytes - 1) as i32);
+            builder.binop(BinaryOp::I32And, sum, mask)
+        } else {
+            let size = builder.i32_const(tape_bytes as i32);
+            builder.binop(BinaryOp::I32RemU, sum, size)
+        }
+    }
+
+    /// The local holding the cell `at` cells from where the pointer started,
+    /// if the tape is in locals.
+    fn tape_local(&self, at: i32) -> Option<LocalId> {
+        self.tape_locals
+            .as_ref()
+            .map(|(first, locals)| locals[(at - first) as usize])
+    }
+
+    /// Truncate `value` to the width of a cell, as storing it in memory
+    /// would.
+    fn truncate(&self, builder: &mut FunctionBuilder, value: ExprId) -> ExprId {
+        if self.cell_bytes == 4 {
+            value
+        } else if self.signed_cells {
+            // Shifting the sign bit up to the top and back again truncates
+            // and sign extends in one go.
+            let shift = builder.i32_const(32 - 8 * self.cell_bytes);
+            let shifted = builder.binop(BinaryOp::I32Shl, value, shift);
+            builder.binop(BinaryOp::I32ShrS, shifted, shift)
+        } else {
+            let max = builder.i32_const(self.cell_max);
+            builder.binop(BinaryOp::I32And, value, max)
+        }
+    }
+
+    /// Trap unless `condition` is nonzero. When reporting traps, the
+    /// `TrapCode` in `reason` goes to `trap` first.
+    fn assert(
+        &self,
+        builder: &mut FunctionBuilder,
+        code: &mut Vec<ExprId>,
+        condition: ExprId,
+        reason: ExprId,
+    ) {
+        let mut check = builder.block(Box::new([]), Box::new([]));
+        let ok_label = check.id();
+        let ok = check.br_if(condition, ok_label, Box::new([]));
+        check.expr(ok);
+        if let Some(trap) = self.trap {
+            let report = check.call(trap, Box::new([reason]));
+            check.expr(report);
+        }
+        let trap = check.unreachable();
+        check.expr(trap);
+        drop(check);
+        code.push(From::from(ok_label));
+    }
+
+    /// With bounds checking, trap unless `address` is a cell on the tape.
+    /// When sanitizing, also keep track of the furthest it's been.
+    fn check_bounds(&self, builder: &mut FunctionBuilder, code: &mut Vec<ExprId>, address: ExprId) {
+        if let Some(last_cell) = self.last_cell {
+            // Addresses below zero look huge as unsigned, so one comparison
+            // checks both ends of the tape.
+            let last_cell = builder.i32_const(last_cell);
+            let in_bounds = builder.binop(BinaryOp::I32LeU, address, last_cell);
+            let below = builder.binop(BinaryOp::I32LtS, address, self.zero);
+            let below_tape = builder.i32_const(TrapCode::BelowTape as i32);
+            let above_tape = builder.i32_const(TrapCode::AboveTape as i32);
+            let reason = builder.select(below, below_tape, above_tape);
+            self.assert(builder, code, in_bounds, reason);
+        }
+        if let Some((high_water, _)) = self.high_water {
+            let highest = builder.global_get(high_water);
+            let higher = builder.binop(BinaryOp::I32GtU, address, highest);
+            let highest = builder.select(higher, address, highest);
+            code.push(builder.global_set(high_water, highest));
+        }
+    }
+
+    /// Code which has to run once the program is done.
+    fn epilogue(&self, builder: &mut FunctionBuilder) -> Vec<ExprId> {
+        let mut code = Vec::new();
+        if let Some((high_water, report)) = self.high_water {
+            let highest = builder.global_get(high_water);
+            let cell_bytes = builder.i32_const(self.cell_bytes);
+            let cell = builder.binop(BinaryOp::I32DivU, highest, cell_bytes);
+            let one = builder.i32_const(1);
+            let cells = builder.binop(BinaryOp::I32Add, cell, one);
+            code.push(builder.call(report, Box::new([cells])));
+        }
+        code
+    }
+
+    /// Evaluate `value` now, returning an expression which reads it back
+    /// without evaluating it again.
+    fn spill(
+        &self,
+        builder: &mut FunctionBuilder,
+        code: &mut Vec<ExprId>,
+        value: ExprId,
+    ) -> ExprId {
+        code.push(builder.local_set(self.scratch, value));
+        builder.local_get(self.scratch)
+    }
+
+    /// The address and memory argument for the cell `at` cells past the
+    /// pointer. Positive offsets fit in the instruction's offset immediate, so
+    /// they don't need an add, unless the tape wraps.
+    fn cell_at(&self, builder: &mut FunctionBuilder, at: i32) -> (ExprId, MemArg) {
+        let bytes = at.wrapping_mul(self.cell_bytes);
+        if bytes > 0 && self.wrap_bytes.is_none() {
+            let arg = MemArg {
+                align: self.cell_arg.align,
+                offset: bytes as u32,
+            };
+            (self.p, arg)
+        } else {
+            (self.address(builder, at), self.cell_arg)
+        }
+    }
+
+    fn load_at(&self, builder: &mut FunctionBuilder, at: i32) -> ExprId {
+        if let Some(local) = self.tape_local(at) {
+            return builder.local_get(local);
+        }
+        if at == 0 {
+            return self.at_p;
+        }
+        let (address, arg) = self.cell_at(builder, at);
+        builder.load(self.memory, self.cell_load, arg, address)
+    }
+
+    fn store_at(&self, builder: &mut FunctionBuilder, at: i32, value: ExprId) -> ExprId {
+        if let Some(local) = self.tape_local(at) {
+            let value = self.truncate(builder, value);
+            return builder.local_set(local, value);
+        }
+        let (address, arg) = self.cell_at(builder, at);
+        builder.store(self.memory, self.cell_store, arg, address, value)
+    }
+
+    /// The value of the current cell.
+    fn cell_value(&self, builder: &mut FunctionBuilder, cursor: Cursor) -> ExprId {
+        match cursor.cell {
+            Cell::Memory => self.load_at(builder, cursor.shift),
+            Cell::Clean | Cell::Dirty => builder.local_get(self.cell),
+        }
+    }
+
+    /// Set the current cell to `value`, which must already be in range if
+    /// the cell is cached.
+    fn set_cell(
+        &self,
+        builder: &mut FunctionBuilder,
+        code: &mut Vec<ExprId>,
+        cursor: &mut Cursor,
+        value: ExprId,
+    ) {
+        match cursor.cell {
+            Cell::Memory => code.push(self.store_at(builder, cursor.shift, value)),
+            Cell::Clean | Cell::Dirty => {
+                code.push(builder.local_set(self.cell, value));
+                cursor.cell = Cell::Dirty;
+            }
+        }
+    }
+
+    /// Make sure memory holds the current cell's latest value, and stop
+    /// caching it.
+    ///
+    /// The cached value is always kept in range, since `add_to_cell` wraps,
+    /// saturates or traps on every add to it, so the store writes the same
+    /// cell as storing after each op would, even though only the store for a
+    /// cell in memory truncates.
+    fn write_back(
+        &self,
+        builder: &mut FunctionBuilder,
+        code: &mut Vec<ExprId>,
+        cursor: &mut Cursor,
+    ) {
+        if cursor.cell == Cell::Dirty {
+            let value = builder.local_get(self.cell);
+            code.push(self.store_at(builder, cursor.shift, value));
+        }
+        cursor.cell = Cell::Memory;
+    }
+
+    /// Apply any moves still pending to the pointer. With the tape in locals,
+    /// the pointer stays where it started.
+    fn sync_pointer(
+        &self,
+        builder: &mut FunctionBuilder,
+        code: &mut Vec<ExprId>,
+        cursor: &mut Cursor,
+    ) {
+        if cursor.shift != 0 && self.tape_locals.is_none() {
+            let p = self.address(builder, cursor.shift);
+            code.push(builder.local_set(self.pointer, p));
+            cursor.shift = 0;
+        }
+    }
+
+    fn add_to_cell(
+        &self,
+        builder: &mut FunctionBuilder,
+        code: &mut Vec<ExprId>,
+        cursor: &mut Cursor,
+        delta: i32,
+    ) {
+        let delta = builder.i32_const(delta);
+        self.add_value(builder, code, cursor, delta);
+    }
+
+    /// Add `delta`, which needn't be a constant, to the current cell.
+    fn add_value(
+        &self,
+        builder: &mut FunctionBuilder,
+        code: &mut Vec<ExprId>,
+        cursor: &mut Cursor,
+        delta: ExprId,
+    ) {
+        let value = self.cell_value(builder, *cursor);
+        let sum = builder.binop(BinaryOp::I32Add, value, delta);
+        let sum = match self.overflow {
+            // Wrapping falls out of the store truncating to the cell width,
+            // but a cached cell has to be truncated by hand.
+            Overflow::Wrap if cursor.cell == Cell::Memory => sum,
+            Overflow::Wrap => self.truncate(builder, sum),
+            Overflow::Saturate => {
+                let sum = self.spill(builder, code, sum);
+                let max = builder.i32_const(self.cell_max);
+                let too_big = builder.binop(BinaryOp::I32GtS, sum, max);
+                let capped = builder.select(too_big, max, sum);
+                let too_small = builder.binop(BinaryOp::I32LtS, sum, self.zero);
+                builder.select(too_small, self.zero, capped)
+            }
+            Overflow::Trap => {
+                // Negative sums look huge as unsigned, so one comparison
+                // checks both ends of the range.
+                let sum = self.spill(builder, code, sum);
+                let max = builder.i32_const(self.cell_max);
+                let in_range = builder.binop(BinaryOp::I32LeU, sum, max);
+                let reason = builder.i32_const(TrapCode::CellOverflow as i32);
+                self.assert(builder, code, in_range, reason);
+                sum
+            }
+        };
+        self.set_cell(builder, code, cursor, sum);
+    }
+
+    /// Store a 32-bit `value` at a fixed offset into the I/O page.
+    fn store_io_word(&self, builder: &mut FunctionBuilder, offset: i32, value: ExprId) -> ExprId {
+        let address = builder.i32_const(self.io_page + offset);
+        let arg = MemArg {
+            align: 4,
+            offset: 0,
+        };
+        builder.store(
+            self.memory,
+            StoreKind::I32 { atomic: false },
+            arg,
+            address,
+            value,
+        )
+    }
+
+    /// Store the low byte of `value` at a fixed offset into the I/O page.
+    fn store_io_byte(&self, builder: &mut FunctionBuilder, offset: i32, value: ExprId) -> ExprId {
+        let address = builder.i32_const(self.io_page + offset);
+        let arg = MemArg {
+            align: 1,
+            offset: 0,
+        };
+        builder.store(
+            self.memory,
+            StoreKind::I32_8 { atomic: false },
+            arg,
+            address,
+            value,
+        )
+    }
+
+    fn load_io_word(&self, builder: &mut FunctionBuilder, offset: i32) -> ExprId {
+        let address = builder.i32_const(self.io_page + offset);
+        let arg = MemArg {
+            align: 4,
+            offset: 0,
+        };
+        builder.load(self.memory, LoadKind::I32 { atomic: false }, arg, address)
+    }
+
+    fn load_io_byte(&self, builder: &mut FunctionBuilder, offset: i32) -> ExprId {
+        let address = builder.i32_const(self.io_page + offset);
+        let arg = MemArg {
+            align: 1,
+            offset: 0,
+        };
+        let kind = LoadKind::I32_8 {
+            kind: ExtendedLoad::ZeroExtend,
+        };
+        builder.load(self.memory, kind, arg, address)
+    }
+
+    /// Code which has to run before the program itself.
+    fn prologue(&self, builder: &mut FunctionBuilder) -> Vec<ExprId> {
+        let mut code = Vec::new();
+        if let Some(pages) = self.grow_to {
+            // A host that provides more than enough memory keeps it all.
+            let size = builder.memory_size(self.memory);
+            let pages = builder.i32_const(pages as i32);
+            let short = builder.binop(BinaryOp::I32Sub, pages, size);
+            let enough = builder.binop(BinaryOp::I32GeU, size, pages);
+            let grow_by = builder.select(enough, self.zero, short);
+            let previous = builder.memory_grow(self.memory, grow_by);
+            let failed = builder.i32_const(-1);
+            let grew = builder.binop(BinaryOp::I32Ne, previous, failed);
+            let reason = builder.i32_const(TrapCode::OutOfMemory as i32);
+            self.assert(builder, &mut code, grew, reason);
+        }
+        if let Some(stream) = self.stream {
+            let stdout = builder.i32_const(WASI_STDOUT);
+            code.push(builder.local_set(stream, stdout));
+        }
+        if let Io::Wasi { .. } = self.io {
+            // The iovecs' buffers never move, and `.` and `,` always
+            // transfer a single byte, so only the batch length ever changes.
+            let byte = builder.i32_const(self.io_page + IO_BYTE);
+            code.push(self.store_io_word(builder, IO_IOVEC, byte));
+            let one = builder.i32_const(1);
+            code.push(self.store_io_word(builder, IO_IOVEC + 4, one));
+            let batch = builder.i32_const(self.io_page + IO_BATCH);
+            code.push(self.store_io_word(builder, IO_BATCH_IOVEC, batch));
+        }
+        code
+    }
+
+    /// Write through WASI's `fd_write` using the iovec at address `iovec`.
+    fn fd_write(&self, builder: &mut FunctionBuilder, code: &mut Vec<ExprId>, iovec: i32) {
+        if let Io::Wasi { fd_write, .. } = self.io {
+            let fd = match self.stream {
+                Some(stream) => builder.local_get(stream),
+                None => builder.i32_const(WASI_STDOUT),
+            };
+            let iovs = builder.i32_const(iovec);
+            let iovs_len = builder.i32_const(1);
+            let nwritten = builder.i32_const(self.io_page + IO_NBYTES);
+            let errno = builder.call(fd_write, Box::new([fd, iovs, iovs_len, nwritten]));
+            code.push(builder.drop(errno));
+        }
+    }
+
+    /// With `strict_ascii_output`, trap unless `value` is printable ASCII or
+    /// whitespace, returning an expression which reads it back.
+    fn check_ascii(
+        &self,
+        builder: &mut FunctionBuilder,
+        code: &mut Vec<ExprId>,
+        value: ExprId,
+    ) -> ExprId {
+        if !self.strict_ascii_output {
+            return value;
+        }
+        let value = self.spill(builder, code, value);
+        // Anything below the space wraps round to look huge, so one
+        // comparison checks both ends of the printable range.
+        let space = builder.i32_const(i32::from(b' '));
+        let past_space = builder.binop(BinaryOp::I32Sub, value, space);
+        let span = builder.i32_const(i32::from(b'~' - b' '));
+        let mut text = builder.binop(BinaryOp::I32LeU, past_space, span);
+        for &byte in b"\n\t\r" {
+            let byte = builder.i32_const(i32::from(byte));
+            let is_byte = builder.binop(BinaryOp::I32Eq, value, byte);
+            text = builder.binop(BinaryOp::I32Or, text, is_byte);
+        }
+        let reason = builder.i32_const(TrapCode::NotAscii as i32);
+        self.assert(builder, code, text, reason);
+        value
+    }
+
+    fn putc(&self, builder: &mut FunctionBuilder, code: &mut Vec<ExprId>, value: ExprId) {
+        match self.io {
+            Io::Env {
+                write: Some(write), ..
+            } => {
+                let stream = self.stream.expect("write needs a stream");
+                let stream = builder.local_get(stream);
+                code.push(builder.call(write, Box::new([stream, value])));
+            }
+            Io::Env { putc, .. } => code.push(builder.call(putc, Box::new([value]))),
+            Io::Wasi { .. } => {
+                code.push(self.store_io_byte(builder, IO_BYTE, value));
+                self.fd_write(builder, code, self.io_page + IO_IOVEC);
+            }
+        }
+    }
+
+    /// Write the first `len` bytes staged in the batch buffer.
+    fn flush(&self, builder: &mut FunctionBuilder, code: &mut Vec<ExprId>, len: u32) {
+        let len = builder.i32_const(len as i32);
+        match self.io {
+            Io::Env { puts, .. } => {
+                let puts = puts.expect("batched output needs puts");
+                let batch = builder.i32_const(self.io_page + IO_BATCH);
+                code.push(builder.call(puts, Box::new([batch, len])));
+            }
+            Io::Wasi { .. } => {
+                code.push(self.store_io_word(builder, IO_BATCH_IOVEC + 4, len));
+                self.fd_write(builder, code, self.io_page + IO_BATCH_IOVEC);
+            }
+        }
+    }
+
+    /// Write the precomputed output, which is `len` bytes.
+    fn print(&self, builder: &mut FunctionBuilder, code: &mut Vec<ExprId>, len: u32) {
+        let iovec = self.precomputed.expect("print needs precomputed output");
+        match self.io {
+            Io::Env { puts, .. } => {
+                let puts = puts.expect("precomputed output needs puts");
+                let output = builder.i32_const(iovec + 8);
+                let len = builder.i32_const(len as i32);
+                code.push(builder.call(puts, Box::new([output, len])));
+            }
+            Io::Wasi { .. } => self.fd_write(builder, code, iovec),
+        }
+    }
+
+    /// Read a byte of input, returning an expression for the byte or -1 at
+    /// the end of input.
+    fn getc(&self, builder: &mut FunctionBuilder, code: &mut Vec<ExprId>) -> ExprId {
+        match self.io {
+            Io::Env {
+                input: EnvInput::Getc(getc),
+                ..
+            } => builder.call(getc, Box::new([])),
+            Io::Env {
+                input: EnvInput::Read(read),
+                ..
+            } => self.read_buffered(builder, code, read),
+            Io::Wasi { fd_read, .. } => {
+                let stdin = builder.i32_const(WASI_STDIN);
+                let iovs = builder.i32_const(self.io_page + IO_IOVEC);
+                let iovs_len = builder.i32_const(1);
+                let nread = builder.i32_const(self.io_page + IO_NBYTES);
+                let errno = builder.call(fd_read, Box::new([stdin, iovs, iovs_len, nread]));
+                code.push(builder.drop(errno));
+
+                // Errors are treated like the end of input.
+                let nread = self.load_io_word(builder, IO_NBYTES);
+                let at_eof = builder.unop(UnaryOp::I32Eqz, nread);
+                let byte = self.load_io_byte(builder, IO_BYTE);
+                let minus_one = builder.i32_const(-1);
+                builder.select(at_eof, minus_one, byte)
+            }
+        }
+    }
+
+    /// Take the next byte from the input buffer, refilling it from `read`
+    /// first if it's empty, returning an expression for the byte or -1 at
+    /// the end of input.
+    fn read_buffered(
+        &self,
+        builder: &mut FunctionBuilder,
+        code: &mut Vec<ExprId>,
+        read: FunctionId,
+    ) -> ExprId {
+        let position = builder.local_get(self.read_position);
+        let len = builder.local_get(self.read_len);
+
+        let mut refill = builder.block(Box::new([]), Box::new([]));
+        let done = refill.id();
+        let not_empty = refill.binop(BinaryOp::I32Ne, position, len);
+        let skip = refill.br_if(not_empty, done, Box::new([]));
+        refill.expr(skip);
+        let buffer = refill.i32_const(self.io_page + IO_READ);
+        let capacity = refill.i32_const(READ_CAPACITY);
+        let got = refill.call(read, Box::new([buffer, capacity]));
+        let set_len = refill.local_set(self.read_len, got);
+        refill.expr(set_len);
+        // Errors are treated like the end of input.
+        let negative = refill.binop(BinaryOp::I32LtS, len, self.zero);
+        let clamped = refill.select(negative, self.zero, len);
+        let clamp = refill.local_set(self.read_len, clamped);
+        refill.expr(clamp);
+        let rewind = refill.local_set(self.read_position, self.zero);
+        refill.expr(rewind);
+        drop(refill);
+        code.push(From::from(done));
+
+        // The buffer is only ever empty here at the end of input.
+        let available = builder.binop(BinaryOp::I32LtU, position, len);
+        let arg = MemArg {
+            align: 1,
+            offset: (self.io_page + IO_READ) as u32,
+        };
+        let kind = LoadKind::I32_8 {
+            kind: ExtendedLoad::ZeroExtend,
+        };
+        let byte = builder.load(self.memory, kind, arg, position);
+        let minus_one = builder.i32_const(-1);
+        let value = builder.select(available, byte, minus_one);
+        let value = self.spill(builder, code, value);
+        let advanced = builder.binop(BinaryOp::I32Add, position, available);
+        code.push(builder.local_set(self.read_position, advanced));
+        value
+    }
+
+    /// Read input into the cell `at` cells from the pointer.
+    fn input(&self, builder: &mut FunctionBuilder, code: &mut Vec<ExprId>, at: i32) {
+        let value = self.getc(builder, code);
+        match self.eof {
+            // The end of input sentinel is already -1.
+            Eof::Passthrough | Eof::MinusOne => code.push(self.store_at(builder, at, value)),
+            Eof::Zero => {
+                let value = self.spill(builder, code, value);
+                let minus_one = builder.i32_const(-1);
+                let at_eof = builder.binop(BinaryOp::I32Eq, value, minus_one);
+                let value = builder.select(at_eof, self.zero, value);
+                code.push(self.store_at(builder, at, value));
+            }
+            Eof::Unchanged => {
+                let value = self.spill(builder, code, value);
+                let mut input = builder.block(Box::new([]), Box::new([]));
+                let done = input.id();
+                let minus_one = input.i32_const(-1);
+                let at_eof = input.binop(BinaryOp::I32Eq, value, minus_one);
+                let skip = input.br_if(at_eof, done, Box::new([]));
+                input.expr(skip);
+                let store = self.store_at(&mut input, at, value);
+                input.expr(store);
+                drop(input);
+                code.push(From::from(done));
+            }
+        }
+    }
+
+    /// Build a single op other than `Open` or `Close`.
+    fn build_op(
+        &self,
+        op: Op,
+        builder: &mut FunctionBuilder,
+        code: &mut Vec<ExprId>,
+        cursor: &mut Cursor,
+    ) {
+        match op {
+            Op::Move(offset)
+                if (!self.defer_moves || self.last_cell.is_some())
+                    && self.tape_locals.is_none() =>
+            {
+                // Leaving the tape has to trap straight away, so there's no
+                // putting off the move when checking bounds.
+                let p = self.address(builder, offset);
+                code.push(builder.local_set(self.pointer, p));
+                self.check_bounds(builder, code, self.p);
+            }
+            Op::Move(offset) => cursor.shift = cursor.shift.wrapping_add(offset),
+            Op::Add(delta) => self.add_to_cell(builder, code, cursor, delta),
+            Op::Output => {
+                let value = self.cell_value(builder, *cursor);
+                let value = self.check_ascii(builder, code, value);
+                self.putc(builder, code, value);
+            }
+            Op::Stage(index) => {
+                let offset = IO_BATCH + index as i32;
+                let value = self.cell_value(builder, *cursor);
+                code.push(self.store_io_byte(builder, offset, value));
+            }
+            Op::Flush(len) => self.flush(builder, code, len),
+            Op::Print(len) => self.print(builder, code, len),
+            Op::Dump => {
+                let dump = self.dump.expect("# needs dump");
+                code.push(builder.call(dump, Box::new([self.p])));
+            }
+            Op::Stream => {
+                let stream = self.stream.expect("~ needs a stream");
+                let value = self.cell_value(builder, *cursor);
+                code.push(builder.local_set(stream, value));
+            }
+            Op::Input => self.input(builder, code, cursor.shift),
+            Op::Clear => self.set_cell(builder, code, cursor, self.zero),
+            Op::Set(value) => {
+                let value = value & self.cell_max;
+                let value = if self.signed_cells && self.cell_bytes < 4 {
+                    let shift = 32 - 8 * self.cell_bytes;
+                    (value << shift) >> shift
+                } else {
+                    value
+                };
+                let value = builder.i32_const(value);
+                self.set_cell(builder, code, cursor, value);
+            }
+            Op::Fill(cells) => {
+                let first = if cells > 0 {
+                    cursor.shift
+                } else {
+                    cursor.shift.wrapping_add(cells).wrapping_add(1)
+                };
+                let address = self.address(builder, first);
+                if self.last_cell.is_some() {
+                    // Check both ends before clearing anything. Memory past a
+                    // trap isn't anybody's business.
+                    let last = first.wrapping_add(cells.abs() - 1);
+                    let last_address = self.address(builder, last);
+                    self.check_bounds(builder, code, address);
+                    self.check_bounds(builder, code, last_address);
+                }
+                let len = builder.i32_const(cells.abs().wrapping_mul(self.cell_bytes));
+                code.push(builder.memory_fill(self.memory, address, self.zero, len));
+            }
+            Op::MulAdd { offset, factor } => {
+                self.multiply_into(builder, code, *cursor, offset, factor, true)
+            }
+            Op::MulSet { offset, factor } => {
+                self.multiply_into(builder, code, *cursor, offset, factor, false)
+            }
+            Op::Scan(step) => self.scan(builder, code, step),
+            Op::Open | Op::Close => unreachable!("loops are built by build"),
+        }
+    }
+
+    /// Add the current cell times `factor` to the cell `offset` cells from
+    /// it, unless the current cell is zero, or with `add` unset, store it
+    /// there.
+    ///
+    /// The loop an add was lowered from never went near the target when its
+    /// counter started out zero, so neither does this: a target off the
+    /// tape only traps if the loop would have run. A store comes from a
+    /// clear of the target ahead of the loop as well, which always happened,
+    /// so it always checks and stores, zero if the counter was.
+    fn multiply_into(
+        &self,
+        builder: &mut FunctionBuilder,
+        code: &mut Vec<ExprId>,
+        cursor: Cursor,
+        offset: i32,
+        factor: i32,
+        add: bool,
+    ) {
+        let at = cursor.shift.wrapping_add(offset);
+        let mut guard = builder.block(Box::new([]), Box::new([]));
+        let skip = guard.id();
+        let mut body = Vec::new();
+        if add {
+            let value = self.cell_value(&mut guard, cursor);
+            let is_zero = guard.unop(UnaryOp::I32Eqz, value);
+            body.push(guard.br_if(is_zero, skip, Box::new([])));
+        }
+        if self.last_cell.is_some() {
+            let address = self.address(&mut guard, at);
+            self.check_bounds(&mut guard, &mut body, address);
+        }
+        let value = self.cell_value(&mut guard, cursor);
+        let product = if factor == 1 {
+            value
+        } else {
+            let factor = guard.i32_const(factor);
+            guard.binop(BinaryOp::I32Mul, value, factor)
+        };
+        let value = if add {
+            let target = self.load_at(&mut guard, at);
+            guard.binop(BinaryOp::I32Add, target, product)
+        } else {
+            product
+        };
+        body.push(self.store_at(&mut guard, at, value));
+        for expr in body {
+            guard.expr(expr);
+        }
+        drop(guard);
+        code.push(From::from(skip));
+    }
+
+    /// When profiling, add one to `counter`.
+    fn count(&self, builder: &mut FunctionBuilder, code: &mut Vec<ExprId>, counter: Counter) {
+        if let Some(counters) = &self.counters {
+            let global = counters[counter.index() as usize];
+            let count = builder.global_get(global);
+            let one = builder.i32_const(1);
+            let count = builder.binop(BinaryOp::I32Add, count, one);
+            code.push(builder.global_set(global, count));
+        }
+    }
+
+    /// With a step limit, trap unless there are steps left, and use one up.
+    fn count_step(&self, builder: &mut FunctionBuilder, code: &mut Vec<ExprId>) {
+        if let Some(steps) = self.steps {
+            let left = builder.global_get(steps);
+            let none = builder.i64_const(0);
+            let some_left = builder.binop(BinaryOp::I64Ne, left, none);
+            let reason = builder.i32_const(TrapCode::StepLimit as i32);
+            self.assert(builder, code, some_left, reason);
+            let one = builder.i64_const(1);
+            let left = builder.binop(BinaryOp::I64Sub, left, one);
+            code.push(builder.global_set(steps, left));
+        }
+    }
+
+    /// Build a loop which moves the pointer by `step` cells until it reaches a
+    /// zero cell.
+    ///
+    /// Stepping back once first lets the loop test at the bottom, so it needs
+    /// neither a wrapping block nor a comparison: the cell itself is the
+    /// condition.
+    fn scan(&self, builder: &mut FunctionBuilder, code: &mut Vec<ExprId>, step: i32) {
+        let back = self.address(builder, -step);
+        code.push(builder.local_set(self.pointer, back));
+        let mut scan = builder.loop_(Box::new([]));
+        let scan_id = scan.id();
+        let forward = self.address(&mut scan, step);
+        let mut body = Vec::new();
+        self.count(&mut scan, &mut body, Counter::Iterations);
+        self.count_step(&mut scan, &mut body);
+        body.push(scan.local_set(self.pointer, forward));
+        self.check_bounds(&mut scan, &mut body, self.p);
+        body.push(scan.br_if(self.at_p, scan_id, Box::new([])));
+        for expr in body {
+            scan.expr(expr);
+        }
+        drop(scan);
+        code.push(From::from(scan_id));
+    }
+
+    /// Wrap the already-built `body` of a loop in the loop itself. The loop
+    /// runs while the cell `at` cells from the pointer is nonzero.
+    fn build_loop(&self, builder: &mut FunctionBuilder, body: Vec<ExprId>, at: i32) -> ExprId {
+        let mut loop_wrapper = builder.block(Box::new([]), Box::new([]));
+        let break_label = loop_wrapper.id();
+        let mut loop_body = loop_wrapper.loop_(Box::new([]));
+        let continue_label = loop_body.id();
+        let cell = self.load_at(&mut loop_body, at);
+        let eq_zero = if self.eqz_loops {
+            loop_body.unop(UnaryOp::I32Eqz, cell)
+        } else {
+            loop_body.binop(BinaryOp::I32Eq, cell, self.zero)
+        };
+        let break_ = loop_body.br_if(eq_zero, break_label, Box::new([]));
+        loop_body.expr(break_);
+        let mut step = Vec::new();
+        self.count(&mut loop_body, &mut step, Counter::Iterations);
+        self.count_step(&mut loop_body, &mut step);
+        for expr in step.into_iter().chain(body) {
+            loop_body.expr(expr);
+        }
+        let continue_ = loop_body.br(continue_label, Box::new([]));
+        loop_body.expr(continue_);
+        drop(loop_body);
+        loop_wrapper.expr(From::from(continue_label));
+        drop(loop_wrapper);
+        From::from(break_label)
+    }
+
+    /// Mark the start of the code for an op from `source`, for
+    /// `append_debug_info` to find.
+    fn mark(
+        &self,
+        builder: &mut FunctionBuilder,
+        code: &mut Vec<ExprId>,
+        sources: &mut Vec<usize>,
+        source: usize,
+    ) {
+        let index = builder.i32_const(sources.len() as i32);
+        code.push(builder.drop(index));
+        sources.push(source);
+    }
+
+    /// Build a whole program, returning the code for its top level. If
+    /// `sources` is given, each op's code is marked, and its source recorded
+    /// there.
+    ///
+    /// Rather than recursing into each loop, this keeps a stack holding the
+    /// code built so far for every loop that's still open, so arbitrarily
+    /// deep nesting doesn't exhaust the native stack.
+    ///
+    /// Within straight-line code, moves are folded into the addresses of the
+    /// cells that follow them, and the pointer itself is only updated once
+    /// the run ends. Within a run of ops which all work on the current cell,
+    /// the cell is kept in a local and only written back once the run ends.
+    fn build(
+        &self,
+        instrs: &[Instr],
+        builder: &mut FunctionBuilder,
+        mut sources: Option<&mut Vec<usize>>,
+    ) -> Vec<ExprId> {
+        let mut code = Vec::new();
+        let mut open_loops = Vec::new();
+        let mut cursor = Cursor {
+            shift: 0,
+            cell: Cell::Memory,
+        };
+        for (i, &instr) in instrs.iter().enumerate() {
+            if let Some(sources) = sources.as_mut() {
+                self.mark(builder, &mut code, sources, instr.source);
+            }
+            if !caches_cell(instr.op) {
+                self.write_back(builder, &mut code, &mut cursor);
+            } else if self.cache_cells
+                && cursor.cell == Cell::Memory
+                && touches_cell(instr.op)
+                && instrs.get(i + 1).map_or(false, |next| caches_cell(next.op))
+            {
+                // A lone op is better off working on memory directly.
+                if reads_cell(instr.op) {
+                    let value = self.load_at(builder, cursor.shift);
+                    code.push(builder.local_set(self.cell, value));
+                }
+                cursor.cell = Cell::Clean;
+            }
+            if !shifts_pointer(instr.op) {
+                self.sync_pointer(builder, &mut code, &mut cursor);
+            }
+            if let Some(counter) = profile::counter_for(instr.op) {
+                self.count(builder, &mut code, counter);
+            }
+            match instr.op {
+                Op::Open => open_loops.push(mem::replace(&mut code, Vec::new())),
+                Op::Close => {
+                    let body = mem::replace(&mut code, open_loops.pop().unwrap());
+                    let expr = self.build_loop(builder, body, cursor.shift);
+                    code.push(expr);
+                }
+                op => self.build_op(op, builder, &mut code, &mut cursor),
+            }
+        }
+        self.write_back(builder, &mut code, &mut cursor);
+        self.sync_pointer(builder, &mut code, &mut cursor);
+        code
+    }
+}
+
+/// The Brainfuck commands in `bf`, at the same offsets, with comments
+/// stripped and translated from another dialect if need be. Anything
+/// `validate` would report is skipped.
+fn commands<'a>(bf: &'a [u8], options: &CompileOptions) -> Cow<'a, [u8]> {
+    let bf = strip_comments(bf, options);
+    match options.dialect {
+        Dialect::Brainfuck => bf,
+        Dialect::Ook => Cow::Owned(ook::translate(&bf, false).0),
+    }
+}
+
+/// Split a source which carries its own input into the program, before the
+/// first `!`, and the input, everything after it. Sources without a `!` have
+/// no input.
+///
+/// Only the first `!` counts, so the input can have `!`s of its own.
+pub fn split_on_bang(source: &[u8]) -> (&[u8], &[u8]) {
+    match source.iter().position(|&byte| byte == b'!') {
+        Some(bang) => (&source[..bang], &source[bang + 1..]),
+        None => (source, &[]),
+    }
+}
+
+/// Check that `options` make sense together.
+fn check_options(options: &CompileOptions) -> Result<(), Error> {
+    if options.overflow != Overflow::Wrap && options.cell_size == CellSize::Bits32 {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "only 8- and 16-bit cells can saturate or trap",
+        ))?;
+    }
+
+    if !options.initial_data.is_empty() {
+        if !options.self_contained_memory && options.target != Target::Wasi {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                "initial data needs a self-contained memory",
+            ))?;
+        }
+        let tape_bytes = u64::from(options.tape_pages().max(1)) * u64::from(PAGE_SIZE);
+        if options.initial_data.len() as u64 > tape_bytes {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                "initial data doesn't fit on the tape",
+            ))?;
+        }
+    }
+
+    if options.pointer_wrap && options.tape_size < options.cell_size.bytes() {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "pointer wrapping needs a tape size",
+        ))?;
+    }
+
+    if options.input_mode == InputMode::Buffered && options.target != Target::Env {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "buffered input needs the env target",
+        ))?;
+    }
+
+    if options.batch_output
+        && options.target == Target::Env
+        && options.has_extension(Extension::Streams)
+    {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "batched output can't switch streams with the env target",
+        ))?;
+    }
+
+    // `puts` only gets bytes, where `putc` would get the whole cell.
+    if options.batch_output
+        && options.target == Target::Env
+        && (options.cell_size != CellSize::Bits8 || options.signed_cells)
+    {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "batched output needs unsigned 8-bit cells with the env target",
+        ))?;
+    }
+
+    // A check failing part way through a batch would lose the output staged
+    // before it.
+    if options.batch_output && options.strict_ascii_output {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "batched output can't check that it's ASCII",
+        ))?;
+    }
+
+    if options.signed_cells && options.overflow != Overflow::Wrap {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "signed cells can only wrap",
+        ))?;
+    }
+
+    if options
+        .max_tape_size
+        .map_or(false, |max| max < options.tape_size)
+    {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "the maximum tape size is less than the minimum",
+        ))?;
+    }
+
+    if options.grow_memory {
+        if !options.initial_data.is_empty() {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                "initial data needs the memory to start out big enough",
+            ))?;
+        }
+        if options.backend == Backend::Interpreter {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the interpreter needs the memory to start out big enough",
+            ))?;
+        }
+    }
+
+    let start_bytes = u64::from(options.start_offset) * u64::from(options.cell_size.bytes());
+    if options.tape_size != 0 && start_bytes >= u64::from(options.tape_size) {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "the start offset isn't on the tape",
+        ))?;
+    } else if start_bytes > i32::max_value() as u64 {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "the start offset is too big",
+        ))?;
+    }
+
+    if options.export_name == TAPE_SIZE_EXPORT
+        || (options.profile && options.export_name == PROFILE_EXPORT)
+        || (options.max_steps > 0 && options.export_name == STEPS_EXPORT)
+    {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "the export name is taken",
+        ))?;
+    }
+
+    if options.sanitize && options.pointer_wrap {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "sanitizing doesn't work with pointer wrapping",
+        ))?;
+    }
+
+    if options.backend == Backend::Interpreter {
+        if options.profile
+            || options.debug_info
+            || options.dwarf
+            || options.batch_output
+            || options.pointer_wrap
+        {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the interpreter can't profile, debug, batch output or wrap the pointer",
+            ))?;
+        }
+        if options.tape_size == 0
+            && !options.self_contained_memory
+            && options.target != Target::Wasi
+        {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the interpreter needs a tape size or its own memory",
+            ))?;
+        }
+    }
+
+    if options.checks_bounds() && options.tape_size < options.cell_size.bytes() {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "bounds checking needs a tape size",
+        ))?;
+    }
+
+    if options.needs_io_page() && options.tape_pages() >= 0xffff {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "no room for an I/O page past the tape",
+        ))?;
+    }
+    Ok(())
+}
+
+fn check_diagnostics(diagnostics: Vec<Diagnostic>) -> Result<(), Error> {
+    if !diagnostics.is_empty() {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            Diagnostics(diagnostics),
+        ))?;
+    }
+    Ok(())
+}
+
+/// Check that a Brainfuck program could be compiled with `options`, without
+/// compiling it.
+///
+/// With `options.deny_warnings`, any `warnings` fail the check too.
+pub fn check(bf: &[u8], options: &CompileOptions) -> Result<(), Error> {
+    check_options(options)?;
+    check_diagnostics(validate(bf, options))?;
+    if options.deny_warnings {
+        check_diagnostics(warnings(bf, options))?;
+    }
+    Ok(())
+}
+
+/// Compile a Brainfuck program to a WebAssembly module, returning the
+/// encoded bytes.
+///
+/// The same source and options always compile to the same bytes. Nothing
+/// here iterates over a hash map, and walrus numbers everything in the
+/// order it was added, so build systems can rely on the output being
+/// reproducible. The producers section does name the versions of bf2wasm
+/// and walrus, so turn it off to compare output between versions.
+pub fn compile(bf: &[u8], options: &CompileOptions) -> Result<Vec<u8>, Error> {
+    let (wasm, _) = compile_with_stats(bf, options)?;
+    Ok(wasm)
+}
+
+/// The name of the function every compiled module exports to report its
+/// `tape_size` in bytes, as an unsigned `i32`, so hosts can size the memory
+/// without being told how it was compiled. Zero means any size will do.
+pub const TAPE_SIZE_EXPORT: &str = "tape_size";
+
+/// The deepest nesting of loops `try_compile` accepts.
+pub const TRY_COMPILE_MAX_DEPTH: usize = 1000;
+
+/// Like `compile`, but returns an error rather than panicking, whatever the
+/// input.
+///
+/// walrus encodes loops recursively, and a stack overflow can't be caught, so
+/// programs with loops nested deeper than `TRY_COMPILE_MAX_DEPTH` are
+/// rejected up front.
+pub fn try_compile(bf: &[u8], options: &CompileOptions) -> Result<Vec<u8>, Error> {
+    let mut depth = 0usize;
+    for &byte in commands(bf, options).iter() {
+        match byte {
+            b'[' => depth += 1,
+            b']' => depth = depth.saturating_sub(1),
+            _ => continue,
+        }
+        if depth > TRY_COMPILE_MAX_DEPTH {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                "loops are nested too deeply",
+            ))?;
+        }
+    }
+    match panic::catch_unwind(|| compile(bf, options)) {
+        Ok(result) => result,
+        Err(_) => Err(Error::new(ErrorKind::Ice, "compilation panicked")),
+    }
+}
+
+/// Compile a Brainfuck program to a walrus module, without encoding it, for
+/// callers who want to transform the module further or encode it
+/// themselves. `compile` is this followed by `emit_wasm`, then validation.
+///
+/// The module isn't validated, whatever `options.validate_output` says.
+/// Debug information describes where code ends up in the encoded module, so
+/// it can't be added until then: `debug_info` and `dwarf` are rejected.
+pub fn compile_to_module(bf: &[u8], options: &CompileOptions) -> Result<Module, Error> {
+    if options.debug_info || options.dwarf {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "debug information needs an encoded module",
+        ))?;
+    }
+    check(bf, options)?;
+    let bf = &commands(bf, options)[..];
+
+    let mut stats = Stats::default();
+    let instrs = ir::parse(bf, options, &mut stats);
+    Ok(build_module(instrs, &mut stats, &mut Vec::new(), options))
+}
+
+/// Compile a Brainfuck program to a function in an existing walrus module,
+/// returning the function's id, so that several programs can go in one
+/// module and be wired together.
+///
+/// The function uses the imports `options` ask for, looked up by name, and
+/// when the memory is self-contained, the memory the module exports as
+/// `memory`. Whichever of them the module doesn't have yet are added.
+/// Every program in the module shares the tape, starting at the same cell.
+/// Nothing is exported: not the function, nor `tape_size` or the like.
+///
+/// Debug information and the interpreter need a module of their own, so
+/// they're rejected.
+pub fn compile_function(
+    bf: &[u8],
+    module: &mut Module,
+    options: &CompileOptions,
+) -> Result<FunctionId, Error> {
+    if options.debug_info || options.dwarf || options.backend == Backend::Interpreter {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "debug information and the interpreter need a module of their own",
+        ))?;
+    }
+    check(bf, options)?;
+    let bf = &commands(bf, options)[..];
+
+    let mut stats = Stats::default();
+    let instrs = ir::parse(bf, options, &mut stats);
+    Ok(add_program(
+        module,
+        instrs,
+        &mut stats,
+        &mut Vec::new(),
+        options,
+        false,
+    ))
+}
+
+/// Compile several Brainfuck programs into one module, which exports each
+/// program's function under the name paired with it, for hosts which want a
+/// suite of routines without a module for each.
+///
+/// Each program is compiled by `compile_function`, so they share the
+/// imports, the memory and so the tape, but each function has a pointer and
+/// locals of its own. Besides the functions, the module only exports
+/// `tape_size`, and the memory if it's self-contained.
+pub fn compile_programs(
+    programs: &[(&str, &[u8])],
+    options: &CompileOptions,
+) -> Result<Vec<u8>, Error> {
+    let mut module = new_module(options);
+    let mut names = Vec::new();
+    for &(name, bf) in programs {
+        if names.contains(&name) || name == TAPE_SIZE_EXPORT || name == "memory" {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("there's already an export named {}", name),
+            ))?;
+        }
+        names.push(name);
+        let function = compile_function(bf, &mut module, options)?;
+        module.exports.add(name, function);
+    }
+    add_tape_size_export(&mut module, options.tape_size);
+    let mut wasm = module
+        .emit_wasm()
+        .map_err(|error| Error::new(ErrorKind::Ice, error.to_string()))?;
+    padding::append_padding(&mut wasm, options.pad_to);
+    if options.validate_output {
+        validate_wasm(&wasm, options)?;
+    }
+    Ok(wasm)
+}
+
+/// Like `compile`, also returning some statistics about the program.
+pub fn compile_with_stats(bf: &[u8], options: &CompileOptions) -> Result<(Vec<u8>, Stats), Error> {
+    check(bf, options)?;
+    let bf = &commands(bf, options)[..];
+
+    let mut stats = Stats::default();
+    let instrs = ir::parse(bf, options, &mut stats);
+    let mut lines = Lines::new();
+    if options.debug_info || options.dwarf {
+        lines.feed(bf);
+    }
+    compile_instrs(instrs, stats, &lines, options)
+}
+
+/// The size of the pieces `compile_reader` reads the source in.
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Like `compile`, but reads the source from `reader` a piece at a time,
+/// rather than needing it all in memory at once.
+///
+/// The source itself is never held in full: only the ops parsed from it so
+/// far, with runs of `+`/`-` and `>`/`<` already folded and comments
+/// dropped, along with the positions of any loops still open. For
+/// machine-generated programs, which tend to be long runs of the same
+/// command, that's much smaller than the source, though it's still
+/// proportional to it, as is the module itself. The slice-based API needs
+/// the whole source as well as all of that.
+///
+/// Ook! has to be translated as a whole, so it's read into memory first.
+pub fn compile_reader<R: Read>(mut reader: R, options: &CompileOptions) -> Result<Vec<u8>, Error> {
+    if options.dialect != Dialect::Brainfuck {
+        let mut bf = Vec::new();
+        reader.read_to_end(&mut bf).context(ErrorKind::Io)?;
+        return compile(&bf, options);
+    }
+
+    check_options(options)?;
+    let mut validator = Validator::new(options, options.strict);
+    let mut parser = ir::Parser::new(options);
+    let mut stats = Stats::default();
+    let mut lines = Lines::new();
+    let mut chunk = vec![0; READ_CHUNK_SIZE];
+    loop {
+        let len = match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(ref error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => Err(Error::new(ErrorKind::Io, error))?,
+        };
+        validator.feed(&chunk[..len]);
+        parser.feed(&chunk[..len], &mut stats);
+        if options.debug_info || options.dwarf || options.deny_warnings {
+            lines.feed(&chunk[..len]);
+        }
+    }
+    check_diagnostics(validator.finish())?;
+    let instrs = parser.finish();
+    if options.deny_warnings {
+        let warnings = lint::infinite_loops(&instrs)
+            .into_iter()
+            .map(|offset| Diagnostic {
+                kind: DiagnosticKind::InfiniteLoop,
+                position: lines.locate(offset),
+            });
+        check_diagnostics(warnings.collect())?;
+    }
+    let (wasm, _) = compile_instrs(instrs, stats, &lines, options)?;
+    Ok(wasm)
+}
+
+/// Optimize and generate code for a parsed program, which has already been
+/// checked. `lines` only needs to know about the source's lines with debug
+/// information.
+fn compile_instrs(
+    instrs: Vec<Instr>,
+    mut stats: Stats,
+    lines: &Lines,
+    options: &CompileOptions,
+) -> Result<(Vec<u8>, Stats), Error> {
+    let mut sources = Vec::new();
+    let mut module = build_module(instrs, &mut stats, &mut sources, options);
+    let mut wasm = module
+        .emit_wasm()
+        .map_err(|error| Error::new(ErrorKind::Ice, error.to_string()))?;
+    if options.debug_info || options.dwarf {
+        debug_info::name_loops(&mut wasm, &sources, lines)?;
+    }
+    if options.debug_info {
+        debug_info::append_debug_info(&mut wasm, &sources)?;
+    }
+    if options.dwarf {
+        debug_info::append_dwarf(&mut wasm, &sources, lines, &options.source_name)?;
+    }
+    padding::append_padding(&mut wasm, options.pad_to);
+    if options.validate_output {
+        validate_wasm(&wasm, options)?;
+    }
+    stats.wasm_size = wasm.len();
+    Ok((wasm, stats))
+}
+
+/// Check that a module compiled with `options` is valid WebAssembly.
+fn validate_wasm(wasm: &[u8], options: &CompileOptions) -> Result<(), Error> {
+    let mut read_options = wabt::ReadBinaryOptions::default();
+    if options.has_feature(Feature::BulkMemory) {
+        read_options.features.enable_bulk_memory();
+    }
+    let module = wabt::Module::read_binary(wasm, &read_options).context(ErrorKind::Ice)?;
+    module.validate().context(ErrorKind::Ice)?;
+    Ok(())
+}
+
+/// An empty module for programs compiled with `options`.
+fn new_module(options: &CompileOptions) -> Module {
+    let mut config = ModuleConfig::new();
+    config.generate_producers_section(options.producers);
+    let mut module = Module::with_config(config);
+    if options.producers {
+        module
+            .producers
+            .add_processed_by("bf2wasm", env!("CARGO_PKG_VERSION"));
+    }
+    module
+}
+
+/// Optimize a parsed program, which has already been checked, and build the
+/// module for it. With debug information, the source of each marker left
+/// in the code goes in `sources`.
+fn build_module(
+    instrs: Vec<Instr>,
+    stats: &mut Stats,
+    sources: &mut Vec<usize>,
+    options: &CompileOptions,
+) -> Module {
+    let mut module = new_module(options);
+    add_program(&mut module, instrs, stats, sources, options, true);
+    module
+}
+
+/// Optimize a parsed program, which has already been checked, and add a
+/// function running it to `module`, along with whatever it needs which the
+/// module doesn't have already. If `standalone` is set, the module is the
+/// program's own, so the function and everything the host would want to
+/// read back are exported too.
+fn add_program(
+    module: &mut Module,
+    mut instrs: Vec<Instr>,
+    stats: &mut Stats,
+    sources: &mut Vec<usize>,
+    options: &CompileOptions,
+    standalone: bool,
+) -> FunctionId {
+    let optimizations = &options.optimizations;
+    let optimized = &mut stats.optimized;
+    // Every `+`, `-`, `>` and `<` would be an op of its own without folding.
+    let commands = stats.increments + stats.decrements + stats.rights + stats.lefts;
+    let runs = instrs.iter().filter(|instr| match instr.op {
+        Op::Add(_) | Op::Move(_) => true,
+        _ => false,
+    });
+    optimized.folded_commands = commands - runs.count();
+    if optimizations.fold_runs {
+        let max = options.cell_size.max_value();
+        instrs = optimize::cancel_inverses(&instrs, options.overflow, max, optimized);
+    }
+    instrs = optimize::lower_loops(&instrs, options.overflow, optimizations, optimized);
+    if optimizations.dead_loops {
+        instrs = optimize::drop_dead_loops(&instrs, optimized);
+    }
+    let mut output = None;
+    if optimizations.precompute_output && standalone && can_precompute(options) {
+        // The tape has at least a page when a size isn't asked for.
+        let tape_bytes = if options.tape_size > 0 {
+            options.tape_size
+        } else {
+            PAGE_SIZE
+        };
+        let first_cell = -i64::from(options.start_offset);
+        let end_cell = first_cell + i64::from(tape_bytes / options.cell_size.bytes());
+        let max = options.cell_size.max_value();
+        if let Some((precomputed, bytes)) =
+            optimize::precompute_output(&instrs, max, first_cell, end_cell, optimized)
+        {
+            instrs = precomputed;
+            output = Some(bytes);
+        }
+    }
+    if optimizations.lower_loops {
+        let max = options.cell_size.max_value();
+        instrs = optimize::assign_copies(&instrs, optimized);
+        instrs = optimize::fold_sets(&instrs, options.overflow, max, optimized);
+        // An unrolled loop doesn't count its steps or iterations any more,
+        // so there's no unrolling while anything's counting them.
+        if optimizations.unroll_loops && options.max_steps == 0 && !options.profile {
+            instrs = optimize::unroll_loops(&instrs, options.overflow, max, optimized);
+        }
+        // A fill can't wrap around the end of the tape, and the interpreter
+        // has no use for one.
+        if options.has_feature(Feature::BulkMemory)
+            && !options.pointer_wrap
+            && options.backend == Backend::Compiled
+        {
+            instrs = optimize::fill_clears(&instrs, optimized);
+        }
+    }
+    if options.batch_output {
+        let can_trap = options.checks_bounds() || options.overflow == Overflow::Trap;
+        instrs = optimize::batch_output(&instrs, BATCH_CAPACITY, can_trap, optimized);
+    }
+    stats.ops = instrs.len();
+
+    let mut builder = FunctionBuilder::new();
+    // The I/O page, if there is one, is the last page the options ask for.
+    // The interpreter's bytecode, or the precomputed output with an iovec
+    // for it in front, goes in pages of its own after that.
+    let pages = options.memory_pages();
+    let io_page = if options.needs_io_page() {
+        (pages - 1) * PAGE_SIZE
+    } else {
+        0
+    };
+    let data_start = pages * PAGE_SIZE;
+    let data = match options.backend {
+        Backend::Compiled => output.as_ref().map(|output| {
+            let mut data = Vec::with_capacity(output.len() + 8);
+            data.extend_from_slice(&(data_start + 8).to_le_bytes());
+            data.extend_from_slice(&(output.len() as u32).to_le_bytes());
+            data.extend_from_slice(output);
+            data
+        }),
+        Backend::Interpreter => Some(interpreter::encode(&instrs, options, data_start)),
+    };
+    let data_pages = data.as_ref().map_or(0, |data| {
+        let page = PAGE_SIZE as usize;
+        ((data.len() + page - 1) / page) as u32
+    });
+    let max_pages = options.max_memory_pages().map(|max| max + data_pages);
+    let pages = pages + data_pages;
+    // With `grow_memory`, any memory will do, as `main` grows it anyway.
+    let declared_pages = if options.grow_memory { 0 } else { pages };
+    let memory = if options.self_contained_memory || options.target == Target::Wasi {
+        let memory = local_memory(module, declared_pages, max_pages);
+        if !options.initial_data.is_empty() {
+            let location = ActiveData {
+                memory: memory,
+                location: ActiveDataLocation::Absolute(0),
+            };
+            module
+                .data
+                .add(DataKind::Active(location), options.initial_data.clone());
+        }
+        memory
+    } else {
+        import_memory(
+            module,
+            &options.import_module,
+            &options.memory_import,
+            declared_pages,
+            max_pages,
+        )
+    };
+    if let Some(data) = data {
+        let location = ActiveData {
+            memory: memory,
+            location: ActiveDataLocation::Absolute(data_start),
+        };
+        module.data.add(DataKind::Active(location), data);
+    }
+
+    let io = match options.target {
+        Target::Env => {
+            let putc_type = module.types.add(&[ValType::I32], &[]);
+            let putc = import_func(
+                module,
+                &options.import_module,
+                &options.putc_import,
+                putc_type,
+            );
+            let input = match options.input_mode {
+                InputMode::Getc => {
+                    let getc_type = module.types.add(&[], &[ValType::I32]);
+                    let getc = import_func(
+                        module,
+                        &options.import_module,
+                        &options.getc_import,
+                        getc_type,
+                    );
+                    EnvInput::Getc(getc)
+                }
+                InputMode::Buffered => {
+                    let read_type = module
+                        .types
+                        .add(&[ValType::I32, ValType::I32], &[ValType::I32]);
+                    let read = import_func(module, &options.import_module, "read", read_type);
+                    EnvInput::Read(read)
+                }
+            };
+            let puts = if options.batch_output || output.is_some() {
+                let puts_type = module.types.add(&[ValType::I32, ValType::I32], &[]);
+                Some(import_func(
+                    module,
+                    &options.import_module,
+                    "puts",
+                    puts_type,
+                ))
+            } else {
+                None
+            };
+            let write = if options.has_extension(Extension::Streams) {
+                let write_type = module.types.add(&[ValType::I32, ValType::I32], &[]);
+                Some(import_func(
+                    module,
+                    &options.import_module,
+                    "write",
+                    write_type,
+                ))
+            } else {
+                None
+            };
+            Io::Env {
+                putc: putc,
+                input: input,
+                puts: puts,
+                write: write,
+            }
+        }
+        Target::Wasi => {
+            let fd_type = module.types.add(&[ValType::I32; 4], &[ValType::I32]);
+            Io::Wasi {
+                fd_write: import_func(module, "wasi_snapshot_preview1", "fd_write", fd_type),
+                fd_read: import_func(module, "wasi_snapshot_preview1", "fd_read", fd_type),
+            }
+        }
+    };
+    // `#` talks to the host the same way whichever target handles the rest of
+    // the I/O.
+    let dump = if options.has_extension(Extension::Debug) {
+        let dump_type = module.types.add(&[ValType::I32], &[]);
+        Some(import_func(
+            module,
+            &options.import_module,
+            "dump",
+            dump_type,
+        ))
+    } else {
+        None
+    };
+    let trap = if options.report_traps {
+        let trap_type = module.types.add(&[ValType::I32], &[]);
+        Some(import_func(
+            module,
+            &options.import_module,
+            "trap",
+            trap_type,
+        ))
+    } else {
+        None
+    };
+    let steps = if options.max_steps > 0 {
+        let budget = InitExpr::Value(Value::I64(options.max_steps as i64));
+        Some(module.globals.add_local(ValType::I64, true, budget))
+    } else {
+        None
+    };
+    let counters = if options.profile {
+        let counters = Counter::ALL.iter().map(|_| {
+            let zero = InitExpr::Value(Value::I32(0));
+            module.globals.add_local(ValType::I32, true, zero)
+        });
+        Some(counters.collect())
+    } else {
+        None
+    };
+    let high_water = if options.sanitize {
+        let start = (options.start_offset * options.cell_size.bytes()) as i32;
+        let start = InitExpr::Value(Value::I32(start));
+        let high_water_type = module.types.add(&[ValType::I32], &[]);
+        let report = import_func(
+            module,
+            &options.import_module,
+            "high_water",
+            high_water_type,
+        );
+        Some((module.globals.add_local(ValType::I32, true, start), report))
+    } else {
+        None
+    };
+    let tape_locals = if options.tape_in_locals
+        && !options.checks_bounds()
+        && options.backend == Backend::Compiled
+    {
+        tape_cells(&instrs, options).map(|(first, last)| {
+            let locals = (first..=last).map(|_| module.locals.add(ValType::I32));
+            (first, locals.collect())
+        })
+    } else {
+        None
+    };
+    let main_func_type = module.types.add(&[], &[]);
+
+    let pointer = module.locals.add(ValType::I32);
+    let p = builder.local_get(pointer);
+    let cell_bytes = options.cell_size.bytes();
+    let cell_arg = if options.force_align_1 {
+        MemArg {
+            align: 1,
+            offset: 0,
+        }
+    } else {
+        options.cell_size.mem_arg()
+    };
+    let context = BfContext {
+        memory: memory,
+        io: io,
+        io_page: io_page as i32,
+        dump: dump,
+        precomputed: output.as_ref().map(|_| data_start as i32),
+        grow_to: if options.grow_memory {
+            Some(pages)
+        } else {
+            None
+        },
+        stream: if options.has_extension(Extension::Streams) {
+            Some(module.locals.add(ValType::I32))
+        } else {
+            None
+        },
+        trap: trap,
+        steps: steps,
+        high_water: high_water,
+        counters: counters,
+        cell_bytes: cell_bytes as i32,
+        cell_arg: cell_arg,
+        cell_load: options.cell_size.load_kind(options.signed_cells),
+        cell_store: options.cell_size.store_kind(),
+        cell_max: options.cell_size.max_value() as i32,
+        signed_cells: options.signed_cells,
+        eof: options.eof,
+        overflow: options.overflow,
+        // A pointer which wraps can't leave the tape.
+        last_cell: if options.checks_bounds() && !options.pointer_wrap {
+            Some((options.tape_size - cell_bytes) as i32)
+        } else {
+            None
+        },
+        wrap_bytes: if options.pointer_wrap {
+            Some(options.tape_size - options.tape_size % cell_bytes)
+        } else {
+            None
+        },
+        pointer: pointer,
+        scratch: module.locals.add(ValType::I32),
+        read_position: module.locals.add(ValType::I32),
+        read_len: module.locals.add(ValType::I32),
+        cell: module.locals.add(ValType::I32),
+        cache_cells: optimizations.cache_cells && tape_locals.is_none(),
+        tape_locals: tape_locals,
+        defer_moves: optimizations.defer_moves,
+        eqz_loops: optimizations.eqz_loops,
+        strict_ascii_output: options.strict_ascii_output,
+        zero: builder.i32_const(0),
+        p: p,
+        at_p: builder.load(
+            memory,
+            options.cell_size.load_kind(options.signed_cells),
+            cell_arg,
+            p,
+        ),
+    };
+
+    let prologue = context.prologue(&mut builder);
+    let code = if options.backend == Backend::Interpreter {
+        let pc = module.locals.add(ValType::I32);
+        let operand = module.locals.add(ValType::I32);
+        interpreter::build(&context, &mut builder, pc, operand, data_start as i32)
+    } else if options.debug_info || options.dwarf {
+        context.build(&instrs, &mut builder, Some(sources))
+    } else {
+        context.build(&instrs, &mut builder, None)
+    };
+    let epilogue = context.epilogue(&mut builder);
+    let start = builder.i32_const((options.start_offset * cell_bytes) as i32);
+    let mut body = vec![builder.local_set(context.pointer, start)];
+    body.extend(prologue.into_iter().chain(code).chain(epilogue));
+    if options.main_block {
+        let mut block = builder.block(Box::new([]), Box::new([]));
+        for expr in body {
+            block.expr(expr);
+        }
+        let block_id = block.id();
+        drop(block);
+        body = vec![From::from(block_id)];
+    }
+
+    let main_func = builder.finish(main_func_type, vec![], body, module);
+    if standalone {
+        module.exports.add(&options.export_name, main_func);
+        if options.start {
+            module.start = Some(main_func);
+        }
+        if let Some(counters) = &context.counters {
+            add_profile_export(module, counters);
+        }
+        if let Some(steps) = context.steps {
+            add_steps_export(module, steps, options.max_steps);
+        }
+        add_tape_size_export(module, options.tape_size);
+    }
+    main_func
+}
+
+/// The function `module` imports as `module_name.name`, importing it with
+/// type `ty` first if it doesn't already.
+fn import_func(module: &mut Module, module_name: &str, name: &str, ty: TypeId) -> FunctionId {
+    let existing = module.imports.iter().find_map(|import| match import.kind {
+        ImportKind::Function(func) if import.module == module_name && import.name == name => {
+            Some(func)
+        }
+        _ => None,
+    });
+    existing.unwrap_or_else(|| module.add_import_func(module_name, name, ty).0)
+}
+
+/// The memory `module` imports as `module_name.name`, importing it first if
+/// it doesn't already.
+fn import_memory(
+    module: &mut Module,
+    module_name: &str,
+    name: &str,
+    pages: u32,
+    max_pages: Option<u32>,
+) -> MemoryId {
+    let existing = module.imports.iter().find_map(|import| match import.kind {
+        ImportKind::Memory(memory) if import.module == module_name && import.name == name => {
+            Some(memory)
+        }
+        _ => None,
+    });
+    existing.unwrap_or_else(|| {
+        module
+            .add_import_memory(module_name, name, false, pages, max_pages)
+            .0
+    })
+}
+
+/// The memory `module` exports as `memory`, defining and exporting it first
+/// if it doesn't already.
+fn local_memory(module: &mut Module, pages: u32, max_pages: Option<u32>) -> MemoryId {
+    let existing = module.exports.iter().find_map(|export| match export.item {
+        ExportItem::Memory(memory) if export.name == "memory" => Some(memory),
+        _ => None,
+    });
+    existing.unwrap_or_else(|| {
+        let memory = module.memories.add_local(false, pages, max_pages);
+        module.exports.add("memory", memory);
+        memory
+    })
+}
+
+/// Whether running the start of a program at compile time gives the same
+/// results as running it for real: cells wrap and start out zero, nothing's
+/// counting what the program does as it goes, and there's somewhere to put
+/// the output past the tape.
+///
+/// Precomputed output is written as bytes, which is all WASI's `fd_write`
+/// ever sees, but `putc` gets the whole cell, so on env cells have to be
+/// unsigned bytes for the bytes to be the same.
+fn can_precompute(options: &CompileOptions) -> bool {
+    options.backend == Backend::Compiled
+        && options.overflow == Overflow::Wrap
+        && options.initial_data.is_empty()
+        && !options.pointer_wrap
+        && !options.sanitize
+        && !options.profile
+        && options.max_steps == 0
+        && !options.grow_memory
+        && !options.strict_ascii_output
+        && (options.target == Target::Wasi
+            || (options.cell_size == CellSize::Bits8 && !options.signed_cells))
+        && (options.tape_size > 0
+            || options.self_contained_memory
+            || options.target == Target::Wasi)
+}
+
+/// The most cells `tape_in_locals` keeps in locals.
+pub const TAPE_LOCALS_MAX: i32 = 64;
+
+/// The first and last cells, relative to where the pointer starts, which a
+/// program compiled with `tape_in_locals` can keep in locals, if it can.
+///
+/// The program mustn't leave the start of the tape, or the end of it if it
+/// has a size, since only memory can trap for that. A tape preloaded with
+/// data or which wraps around is left in memory.
+fn tape_cells(instrs: &[Instr], options: &CompileOptions) -> Option<(i32, i32)> {
+    if options.pointer_wrap || !options.initial_data.is_empty() {
+        return None;
+    }
+    let (first, last) = optimize::static_cells(instrs)?;
+    let start = i64::from(options.start_offset);
+    let cells = i64::from(options.tape_size / options.cell_size.bytes());
+    let on_tape = start + i64::from(first) >= 0 && (cells == 0 || start + i64::from(last) < cells);
+    if on_tape && i64::from(last) - i64::from(first) < i64::from(TAPE_LOCALS_MAX) {
+        Some((first, last))
+    } else {
+        None
+    }
+}
+
+/// Export a function reading back the profile counters in `counters`.
+fn add_profile_export(module: &mut Module, counters: &[GlobalId]) {
+    let mut builder = FunctionBuilder::new();
+    let index = module.locals.add(ValType::I32);
+    let requested = builder.local_get(index);
+    let mut count = builder.i32_const(0);
+    for (i, &global) in counters.iter().enumerate() {
+        let i = builder.i32_const(i as i32);
+        let is_this = builder.binop(BinaryOp::I32Eq, requested, i);
+        let this = builder.global_get(global);
+        count = builder.select(is_this, this, count);
+    }
+    let profile_type = module.types.add(&[ValType::I32], &[ValType::I32]);
+    let profile = builder.finish(profile_type, vec![index], vec![count], module);
+    module.exports.add(PROFILE_EXPORT, profile);
+}
+
+/// The name of the function a module compiled with `max_steps` exports to
+/// report how many steps the program has taken, as an `i64`.
+pub const STEPS_EXPORT: &str = "steps";
+
+/// Export a function working out the steps taken from the `steps` left out
+/// of `max_steps`.
+fn add_steps_export(module: &mut Module, steps: GlobalId, max_steps: u64) {
+    let mut builder = FunctionBuilder::new();
+    let max_steps = builder.i64_const(max_steps as i64);
+    let left = builder.global_get(steps);
+    let taken = builder.binop(BinaryOp::I64Sub, max_steps, left);
+    let steps_type = module.types.add(&[], &[ValType::I64]);
+    let steps = builder.finish(steps_type, vec![], vec![taken], module);
+    module.exports.add(STEPS_EXPORT, steps);
+}
+
+/// Export a function returning the tape size the module was compiled with.
+fn add_tape_size_export(module: &mut Module, tape_size: u32) {
+    let mut builder = FunctionBuilder::new();
+    let size = builder.i32_const(tape_size as i32);
+    let tape_size_type = module.types.add(&[], &[ValType::I32]);
+    let tape_size = builder.finish(tape_size_type, vec![], vec![size], module);
+    module.exports.add(TAPE_SIZE_EXPORT, tape_size);
+}
+
+/// Convert an encoded WebAssembly module to the WebAssembly text format.
+pub fn to_wat(wasm: &[u8]) -> Result<String, Error> {
+    let wat = wabt::wasm2wat(wasm).context(ErrorKind::Ice)?;
+    Ok(wat)
+}
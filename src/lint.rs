@@ -0,0 +1,43 @@
+use crate::ir::{Instr, Op};
+
+/// The source offsets of loops which can never end once they've started.
+///
+/// It only spots loops whose body is straight-line code bringing the pointer
+/// back to the cell the loop tests, without ever changing that cell, such as
+/// `[]` or `[>+<]`. Loops at the start of the program or straight after
+/// another loop can't start at all, and are often comments, so they're left
+/// out.
+pub(crate) fn infinite_loops(instrs: &[Instr]) -> Vec<usize> {
+    let mut loops = Vec::new();
+    for (i, instr) in instrs.iter().enumerate() {
+        if instr.op != Op::Open {
+            continue;
+        }
+        let can_start = match i.checked_sub(1).map(|before| instrs[before].op) {
+            None | Some(Op::Close) => false,
+            Some(_) => true,
+        };
+        if can_start && never_ends(&instrs[i + 1..]) {
+            loops.push(instr.source);
+        }
+    }
+    loops
+}
+
+/// Whether the loop whose body starts at `body` leaves its cell as it found
+/// it every time round.
+fn never_ends(body: &[Instr]) -> bool {
+    let mut offset = 0i64;
+    let mut delta = 0i64;
+    for instr in body {
+        match instr.op {
+            Op::Move(n) => offset += i64::from(n),
+            Op::Add(n) if offset == 0 => delta += i64::from(n),
+            Op::Add(_) | Op::Output | Op::Dump | Op::Stream => {}
+            Op::Input if offset != 0 => {}
+            Op::Close => return offset == 0 && delta == 0,
+            _ => return false,
+        }
+    }
+    false
+}
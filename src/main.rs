@@ -1,180 +1,70 @@
-use clap::{App, Arg};
-use failure::{Backtrace, Context, Fail, ResultExt};
-use std::fmt::{self, Display};
-use std::fs;
-use walrus::ir::{BinaryOp, ExprId, ExtendedLoad, LoadKind, MemArg, StoreKind};
-use walrus::{
-    BlockBuilder, FunctionBuilder, FunctionId, LocalId, MemoryId, Module, ModuleConfig, ValType,
-};
-
-#[derive(Copy, Clone, Eq, PartialEq, Debug, Fail)]
-pub enum ErrorKind {
-    #[fail(display = "I/O error")]
-    Io,
-
-    #[fail(display = "Invalid input")]
-    InvalidInput,
-
-    #[fail(display = "Internal error; unable to generate WebAssembly")]
-    Ice,
-}
+mod cache;
 
-#[derive(Debug)]
-pub struct Error {
-    inner: Context<ErrorKind>,
-}
+use crate::cache::{short_hash, Cache};
+use bf2wasm::{
+    check, check_output, compile_programs, compile_with_stats, expected_output, html_harness,
+    manifest, option_header, run_with_options, split_on_bang, to_wat, warnings, Backend, CellSize,
+    CompileOptions, Diagnostic, Dialect, Eof, Error, ErrorKind, Extension, Feature, InputMode,
+    Optimizations, Overflow, Stats, Target,
+};
+use clap::{value_t, values_t, App, Arg, ArgMatches};
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-impl Fail for Error {
-    fn cause(&self) -> Option<&Fail> {
-        self.inner.cause()
-    }
+/// How often `--watch` looks for changes to the input.
+const WATCH_INTERVAL: Duration = Duration::from_millis(250);
 
-    fn backtrace(&self) -> Option<&Backtrace> {
-        self.inner.backtrace()
+fn main() {
+    // Kept for merging with the options in a source's header.
+    let args: Vec<OsString> = env::args_os().collect();
+    let matches = app().get_matches_from(&args);
+    if let Err(error) = try_main(&matches, &args) {
+        report(&matches, &error, "bf2wasm");
+        process::exit(1);
     }
 }
 
-impl Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        Display::fmt(&self.inner, f)
-    }
-}
-
-impl Error {
-    pub fn kind(&self) -> ErrorKind {
-        *self.inner.get_context()
+/// Print `warnings` about the source to standard error, like `report`.
+fn warn(matches: &ArgMatches, warnings: &[Diagnostic], prefix: &str) {
+    for warning in warnings {
+        if matches.value_of("message-format") == Some("json") {
+            eprintln!("{}", warning.to_json());
+        } else {
+            eprintln!("{}: {}", prefix, warning);
+        }
     }
 }
 
-impl From<ErrorKind> for Error {
-    fn from(kind: ErrorKind) -> Error {
-        Error {
-            inner: Context::new(kind),
+/// Print `error` to standard error in the format the user asked for, with
+/// every line of the human format starting with `prefix`.
+fn report(matches: &ArgMatches, error: &Error, prefix: &str) {
+    if matches.value_of("message-format") == Some("json") {
+        eprintln!("{}", error.to_json());
+    } else if error.diagnostics().is_empty() {
+        eprintln!("{}: {}", prefix, error.message());
+    } else {
+        for diagnostic in error.diagnostics() {
+            eprintln!("{}: {}", prefix, diagnostic);
         }
     }
 }
 
-impl From<Context<ErrorKind>> for Error {
-    fn from(inner: Context<ErrorKind>) -> Error {
-        Error { inner: inner }
-    }
+fn io_error(error: io::Error) -> Error {
+    Error::new(ErrorKind::Io, error)
 }
 
-struct BfContext {
-    memory: MemoryId,
-    putc_func: FunctionId,
-    getc_func: FunctionId,
-
-    one_byte: MemArg,
-    pointer: LocalId,
-    zero: ExprId,
-    one: ExprId,
-    p: ExprId,
-    at_p: ExprId,
+fn arg_error(error: clap::Error) -> Error {
+    Error::new(ErrorKind::InvalidInput, error.message)
 }
 
-impl BfContext {
-    fn build(
-        &self,
-        bf: &[u8],
-        builder: &mut BlockBuilder,
-        consume_all: bool,
-    ) -> Result<usize, Error> {
-        let mut i = 0;
-        while i < bf.len() {
-            let byte = bf[i];
-            match byte {
-                b'>' => {
-                    let p = builder.binop(BinaryOp::I32Add, self.p, self.one);
-                    let set = builder.local_set(self.pointer, p);
-                    builder.expr(set);
-                    i += 1;
-                }
-                b'<' => {
-                    let p = builder.binop(BinaryOp::I32Sub, self.p, self.one);
-                    let set = builder.local_set(self.pointer, p);
-                    builder.expr(set);
-                    i += 1;
-                }
-                b'+' => {
-                    let at_p = builder.binop(BinaryOp::I32Add, self.at_p, self.one);
-                    let store = builder.store(
-                        self.memory,
-                        StoreKind::I32_8 { atomic: false },
-                        self.one_byte,
-                        self.p,
-                        at_p,
-                    );
-                    builder.expr(store);
-                    i += 1;
-                }
-                b'-' => {
-                    let at_p = builder.binop(BinaryOp::I32Sub, self.at_p, self.one);
-                    let store = builder.store(
-                        self.memory,
-                        StoreKind::I32_8 { atomic: false },
-                        self.one_byte,
-                        self.p,
-                        at_p,
-                    );
-                    builder.expr(store);
-                    i += 1;
-                }
-                b'.' => {
-                    let call = builder.call(self.putc_func, Box::new([self.at_p]));
-                    builder.expr(call);
-                    i += 1;
-                }
-                b',' => {
-                    let at_p = builder.call(self.getc_func, Box::new([]));
-                    let store = builder.store(
-                        self.memory,
-                        StoreKind::I32_8 { atomic: false },
-                        self.one_byte,
-                        self.p,
-                        at_p,
-                    );
-                    builder.expr(store);
-                    i += 1;
-                }
-                b'[' => {
-                    let mut loop_wrapper = builder.block(Box::new([]), Box::new([]));
-                    let break_label = loop_wrapper.id();
-                    let mut loop_body = loop_wrapper.loop_(Box::new([]));
-                    let continue_label = loop_body.id();
-                    let eq_zero = loop_body.binop(BinaryOp::I32Eq, self.at_p, self.zero);
-                    let break_ = loop_body.br_if(eq_zero, break_label, Box::new([]));
-                    loop_body.expr(break_);
-                    i += 1;
-                    i += self.build(&bf[i..], &mut loop_body, false)?;
-                    let continue_ = loop_body.br(continue_label, Box::new([]));
-                    loop_body.expr(continue_);
-                    drop(loop_body);
-                    loop_wrapper.expr(From::from(continue_label));
-                    drop(loop_wrapper);
-                    builder.expr(From::from(break_label));
-                }
-                b']' => {
-                    i += 1;
-                    break;
-                }
-                _ => {
-                    Err(ErrorKind::InvalidInput)?;
-                }
-            }
-        }
-        if consume_all && i < bf.len() {
-            Err(ErrorKind::InvalidInput)?;
-        } else if !consume_all && i == bf.len() {
-            Err(ErrorKind::InvalidInput)?;
-        }
-
-        Ok(i)
-    }
-}
-
-fn main() -> Result<(), Error> {
-    let matches = App::new("bf2wasm")
+fn app() -> App<'static, 'static> {
+    App::new("bf2wasm")
         .version("0.1")
         .author("Keith Bauer <onesadcookie@gmail.com>")
         .about("Convert Brainfuck to WebAssembly")
@@ -183,70 +73,939 @@ fn main() -> Result<(), Error> {
                 .short("i")
                 .long("input")
                 .value_name("FILE.bf")
-                .help("The Brainfuck source to compile")
+                .help("Brainfuck source to compile, or - for standard input; repeat to join files")
                 .takes_value(true)
-                .required(true),
+                .multiple(true)
+                .number_of_values(1)
+                .required_unless_one(&["batch", "multi"]),
         )
         .arg(
             Arg::with_name("output")
                 .short("o")
                 .long("output")
                 .value_name("FILE.wasm")
-                .help("The WebAssembly output file")
+                .help("The WebAssembly output file, or - for standard output")
+                .takes_value(true)
+                .required_unless_one(&["run", "check", "batch"]),
+        )
+        .arg(
+            Arg::with_name("batch")
+                .long("batch")
+                .value_name("DIR")
+                .help("Compile every .bf file in a directory to a .wasm file next to it")
+                .takes_value(true)
+                .conflicts_with_all(&[
+                    "input",
+                    "output",
+                    "run",
+                    "check",
+                    "emit-html",
+                    "emit-manifest",
+                ]),
+        )
+        .arg(
+            Arg::with_name("multi")
+                .long("multi")
+                .value_name("NAME=FILE.bf")
+                .help("Compile each program into one module, exported as NAME; repeat for each")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with_all(&[
+                    "input",
+                    "batch",
+                    "run",
+                    "check",
+                    "watch",
+                    "emit-html",
+                    "emit-manifest",
+                    "cache-dir",
+                ]),
+        )
+        .arg(
+            Arg::with_name("cache-dir")
+                .long("cache-dir")
+                .value_name("DIR")
+                .help("Reuse modules compiled before from the same source with the same options")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("emit-wat")
+                .long("emit-wat")
+                .help("Write the WebAssembly text format instead of the binary format"),
+        )
+        .arg(
+            Arg::with_name("emit-html")
+                .long("emit-html")
+                .help("Also write an HTML page which runs the output, next to it"),
+        )
+        .arg(
+            Arg::with_name("emit-manifest")
+                .long("emit-manifest")
+                .help("Also write a JSON file describing the output's imports and exports, next to it"),
+        )
+        .arg(
+            Arg::with_name("dialect")
+                .long("dialect")
+                .value_name("DIALECT")
+                .help("The language the source is written in")
+                .takes_value(true)
+                .possible_values(&["brainfuck", "ook"])
+                .default_value("brainfuck"),
+        )
+        .arg(
+            Arg::with_name("strict")
+                .long("strict")
+                .help("Reject characters other than Brainfuck commands"),
+        )
+        .arg(
+            Arg::with_name("deny-warnings")
+                .long("deny-warnings")
+                .help("Fail on warnings about the source, such as loops which can never end"),
+        )
+        .arg(
+            Arg::with_name("max-nesting")
+                .long("max-nesting")
+                .value_name("DEPTH")
+                .help("Reject loops nested more than this deep, or 0 for no limit")
+                .takes_value(true)
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("tape-size")
+                .long("tape-size")
+                .value_name("BYTES")
+                .help("The minimum size of the tape, rounded up to whole 64 KiB pages")
+                .takes_value(true)
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("max-tape-size")
+                .long("max-tape-size")
+                .value_name("BYTES")
+                .help("The most the tape may grow to, rounded up to whole 64 KiB pages")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("start-offset")
+                .long("start-offset")
+                .value_name("CELLS")
+                .help("The cell the pointer starts at")
+                .takes_value(true)
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("self-contained-memory")
+                .long("self-contained-memory")
+                .help("Define and export the tape's memory instead of importing it"),
+        )
+        .arg(
+            Arg::with_name("grow-memory")
+                .long("grow-memory")
+                .help("Grow the memory to fit the tape when main starts, rather than requiring it"),
+        )
+        .arg(
+            Arg::with_name("target")
+                .long("target")
+                .value_name("TARGET")
+                .help("The host interface to use for input and output")
                 .takes_value(true)
-                .required(true),
+                .possible_values(&["env", "wasi"])
+                .default_value("env"),
+        )
+        .arg(
+            Arg::with_name("backend")
+                .long("backend")
+                .value_name("BACKEND")
+                .help("Compile each op, or interpret bytecode for a smaller, slower module")
+                .takes_value(true)
+                .possible_values(&["compiled", "interpreter"])
+                .default_value("compiled"),
+        )
+        .arg(
+            Arg::with_name("initial-data")
+                .long("initial-data")
+                .value_name("FILE")
+                .help("Preload the start of the tape with a file; needs --self-contained-memory")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("standalone")
+                .long("standalone")
+                .help("Make a WASI command that runs with no imports to provide, as with wasmtime run")
+                .conflicts_with_all(&["target", "self-contained-memory", "export-name", "start"]),
+        )
+        .arg(
+            Arg::with_name("batch-output")
+                .long("batch-output")
+                .help("Write consecutive output with a single call, through env.puts for env"),
+        )
+        .arg(
+            Arg::with_name("input-mode")
+                .long("input-mode")
+                .value_name("MODE")
+                .help("How , reads input: a byte at a time, or buffered through env.read")
+                .takes_value(true)
+                .possible_values(&["getc", "buffered"])
+                .default_value("getc"),
+        )
+        .arg(
+            Arg::with_name("cell-size")
+                .long("cell-size")
+                .value_name("BITS")
+                .help("The width of each cell on the tape")
+                .takes_value(true)
+                .possible_values(&["8", "16", "32"])
+                .default_value("8"),
+        )
+        .arg(
+            Arg::with_name("signed-cells")
+                .long("signed-cells")
+                .help("Treat cells as signed, so . passes negative values to putc"),
+        )
+        .arg(
+            Arg::with_name("force-align-1")
+                .long("force-align-1")
+                .help("Don't promise the engine that cells wider than a byte are aligned"),
+        )
+        .arg(
+            Arg::with_name("cell-overflow")
+                .long("cell-overflow")
+                .value_name("MODE")
+                .help("What + and - do at the limits of a cell")
+                .takes_value(true)
+                .possible_values(&["wrap", "saturate", "trap"])
+                .default_value("wrap"),
+        )
+        .arg(
+            Arg::with_name("eof")
+                .long("eof")
+                .value_name("MODE")
+                .help("What , stores once getc returns -1 for the end of input")
+                .takes_value(true)
+                .possible_values(&["passthrough", "zero", "minus-one", "unchanged"])
+                .default_value("passthrough"),
+        )
+        .arg(
+            Arg::with_name("bounds-check")
+                .long("bounds-check")
+                .help("Trap if the pointer leaves the tape; needs --tape-size"),
+        )
+        .arg(
+            Arg::with_name("pointer-wrap")
+                .long("pointer-wrap")
+                .help("Wrap the pointer around the ends of the tape; needs --tape-size"),
         )
-        .get_matches();
+        .arg(
+            Arg::with_name("sanitize")
+                .long("sanitize")
+                .help("Check bounds and report how much of the tape was used; needs --tape-size"),
+        )
+        .arg(
+            Arg::with_name("tape-in-locals")
+                .long("tape-in-locals")
+                .help("Keep the tape in locals instead of memory, for small programs which allow it"),
+        )
+        .arg(
+            Arg::with_name("strict-ascii-output")
+                .long("strict-ascii-output")
+                .help("Trap if . writes anything but printable ASCII and whitespace"),
+        )
+        .arg(
+            Arg::with_name("report-traps")
+                .long("report-traps")
+                .help("Tell the host why the program traps through an env.trap(code) import"),
+        )
+        .arg(
+            Arg::with_name("debug-info")
+                .long("debug-info")
+                .help("Add a custom section mapping code back to the source"),
+        )
+        .arg(
+            Arg::with_name("debug")
+                .long("debug")
+                .value_name("FORMAT")
+                .possible_values(&["dwarf"])
+                .help("Add standard debug information, for debuggers to find lines in the source"),
+        )
+        .arg(
+            Arg::with_name("line-comments")
+                .long("line-comments")
+                .help("Ignore everything from ; to the end of the line, even with --strict"),
+        )
+        .arg(
+            Arg::with_name("extension")
+                .long("extension")
+                .value_name("NAME")
+                .help(
+                    "Recognize an extra command: debug for # to call env.dump, or streams for ~ \
+                     to make the current cell the stream . writes to",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .possible_values(&["debug", "streams"]),
+        )
+        .arg(
+            Arg::with_name("start")
+                .long("start")
+                .help("Also run main as the start function, when the module is instantiated"),
+        )
+        .arg(
+            Arg::with_name("export-name")
+                .long("export-name")
+                .value_name("NAME")
+                .help("The name to export the program's function as")
+                .takes_value(true)
+                .default_value("main"),
+        )
+        .arg(
+            Arg::with_name("import-module")
+                .long("import-module")
+                .value_name("NAME")
+                .help("The module the host's imports come from")
+                .takes_value(true)
+                .default_value("env"),
+        )
+        .arg(
+            Arg::with_name("memory-import")
+                .long("memory-import")
+                .value_name("NAME")
+                .help("The field the tape's memory is imported as")
+                .takes_value(true)
+                .default_value("memory"),
+        )
+        .arg(
+            Arg::with_name("putc-import")
+                .long("putc-import")
+                .value_name("NAME")
+                .help("The field putc is imported as")
+                .takes_value(true)
+                .default_value("putc"),
+        )
+        .arg(
+            Arg::with_name("getc-import")
+                .long("getc-import")
+                .value_name("NAME")
+                .help("The field getc is imported as")
+                .takes_value(true)
+                .default_value("getc"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .help("Count the ops of each kind the program runs; --run prints the counts"),
+        )
+        .arg(
+            Arg::with_name("max-steps")
+                .long("max-steps")
+                .value_name("COUNT")
+                .help("Trap after this many loop iterations, or 0 for no limit")
+                .takes_value(true)
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("opt-level")
+                .short("O")
+                .value_name("LEVEL")
+                .help("How much to optimize: 0 for none, up to 3 for everything")
+                .takes_value(true)
+                .possible_values(&["0", "1", "2", "3"])
+                .default_value("3"),
+        )
+        .arg(
+            Arg::with_name("enable")
+                .long("enable")
+                .value_name("FEATURE")
+                .help(
+                    "Let the module use a proposal the host supports: bulk-memory for memory.fill",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .possible_values(&["bulk-memory"]),
+        )
+        .arg(
+            Arg::with_name("pad-to")
+                .long("pad-to")
+                .value_name("BYTES")
+                .help("Pad the module with a custom section to a multiple of this size")
+                .takes_value(true)
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("no-producers")
+                .long("no-producers")
+                .help("Leave out the producers section naming the tools which made the module"),
+        )
+        .arg(
+            Arg::with_name("no-main-block")
+                .long("no-main-block")
+                .help("Put the program straight into the main function, not in a block of its own"),
+        )
+        .arg(
+            Arg::with_name("check")
+                .long("check")
+                .help("Only check the source for problems, without compiling it")
+                .conflicts_with_all(&["output", "run"]),
+        )
+        .arg(
+            Arg::with_name("validate")
+                .long("validate")
+                .help("Validate the module before writing it; always on in debug builds"),
+        )
+        .arg(
+            Arg::with_name("stats")
+                .long("stats")
+                .help("Print statistics about the program to standard error"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .help("Print what the optimizer did to standard error"),
+        )
+        .arg(
+            Arg::with_name("summary")
+                .long("summary")
+                .help("Print where the output went, its size and a hash of it to standard error"),
+        )
+        .arg(
+            Arg::with_name("message-format")
+                .long("message-format")
+                .value_name("FORMAT")
+                .help("How to print errors and --stats: json prints a JSON object per line")
+                .takes_value(true)
+                .possible_values(&["human", "json"])
+                .default_value("human"),
+        )
+        .arg(
+            Arg::with_name("run")
+                .long("run")
+                .help("Run the compiled program, connected to standard input and output"),
+        )
+        .arg(
+            Arg::with_name("run-input")
+                .long("run-input")
+                .value_name("FILE")
+                .help("Read the input for --run from a file instead of standard input")
+                .takes_value(true)
+                .requires("run"),
+        )
+        .arg(
+            Arg::with_name("run-output")
+                .long("run-output")
+                .value_name("FILE")
+                .help("Write the output of --run to a file instead of standard output")
+                .takes_value(true)
+                .requires("run"),
+        )
+        .arg(
+            Arg::with_name("time")
+                .long("time")
+                .help("Print how long --run took, and how many steps with --max-steps")
+                .requires("run"),
+        )
+        .arg(
+            Arg::with_name("split-on-bang")
+                .long("split-on-bang")
+                .help("Treat everything after the first ! as input for --run, rather than program")
+                .conflicts_with("run-input"),
+        )
+        .arg(
+            Arg::with_name("watch")
+                .long("watch")
+                .help("Compile again whenever an input file changes, until interrupted")
+                .conflicts_with_all(&["batch", "check"]),
+        )
+}
 
-    let input_path = matches.value_of_os("input").unwrap();
-    let bf = fs::read(input_path).context(ErrorKind::Io)?;
+/// Do whatever `matches`, parsed from the command line `args`, ask for.
+fn try_main(matches: &ArgMatches, args: &[OsString]) -> Result<(), Error> {
+    if let Some(dir) = matches.value_of_os("batch") {
+        return batch(matches, args, Path::new(dir));
+    }
+    if matches.is_present("multi") {
+        return multi(matches);
+    }
+    if matches.is_present("watch") {
+        return watch(matches, args);
+    }
+    build(matches, args)
+}
 
-    let output_path = matches.value_of_os("output").unwrap();
+/// The time of day, in UTC, for `--watch` to say when it built.
+fn timestamp() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600 % 24,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
 
-    // Construct a Walrus module.
-    let config = ModuleConfig::new();
-    let mut module = Module::with_config(config);
+/// Build the inputs, and build them again whenever any of them is modified,
+/// saying how each build went. This only stops when it's interrupted.
+fn watch(matches: &ArgMatches, args: &[OsString]) -> Result<(), Error> {
+    let paths: Vec<&OsStr> = matches.values_of_os("input").unwrap().collect();
+    if paths.contains(&OsStr::new("-")) {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--watch can't watch standard input",
+        ))?;
+    }
+    let mut built = None;
+    loop {
+        // A file which can't be looked at counts as a change too, and the
+        // build will say what's wrong with it.
+        let modified: Vec<Option<SystemTime>> = paths
+            .iter()
+            .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+            .collect();
+        if built.as_ref() != Some(&modified) {
+            built = Some(modified);
+            match build(matches, args) {
+                Ok(()) => eprintln!("bf2wasm: {}: ok", timestamp()),
+                Err(error) => {
+                    report(matches, &error, "bf2wasm");
+                    eprintln!("bf2wasm: {}: failed", timestamp());
+                }
+            }
+        }
+        thread::sleep(WATCH_INTERVAL);
+    }
+}
 
-    let putc_type = module.types.add(&[ValType::I32], &[]);
-    let getc_type = module.types.add(&[], &[ValType::I32]);
-    let main_func_type = module.types.add(&[], &[]);
+/// Compile the input files as one program, and do whatever the command line
+/// asks with it.
+fn build(matches: &ArgMatches, args: &[OsString]) -> Result<(), Error> {
+    // Brackets can span files, so they're all compiled as one program.
+    let mut bf = Vec::new();
+    for input_path in matches.values_of_os("input").unwrap() {
+        if input_path == OsStr::new("-") {
+            io::stdin().read_to_end(&mut bf).map_err(io_error)?;
+        } else {
+            bf.extend(fs::read(input_path).map_err(io_error)?);
+        }
+    }
 
-    let mut builder = FunctionBuilder::new();
-    let (memory, _) = module.add_import_memory("env", "memory", false, 0, None);
-    let pointer = module.locals.add(ValType::I32);
-    let p = builder.local_get(pointer);
-    let zext_u8 = LoadKind::I32_8 {
-        kind: ExtendedLoad::ZeroExtend,
+    // The source's own options come before the command line's, which win.
+    let header_matches;
+    let (matches, bf) = match option_header(&bf) {
+        Some((header, blanked)) => {
+            header_matches = with_header(matches, args, &header)?;
+            (&header_matches, blanked)
+        }
+        None => (matches, bf),
     };
-    let one_byte = walrus::ir::MemArg {
-        align: 1,
-        offset: 0,
+
+    let source_name = match matches.values_of_os("input").unwrap().next() {
+        Some(path) if path != OsStr::new("-") => path.to_string_lossy().into_owned(),
+        _ => String::from("stdin"),
     };
-    let context = BfContext {
-        memory: memory,
-        putc_func: module.add_import_func("env", "putc", putc_type).0,
-        getc_func: module.add_import_func("env", "getc", getc_type).0,
-        one_byte: one_byte,
-        pointer: pointer,
-        zero: builder.i32_const(0),
-        one: builder.i32_const(1),
-        p: p,
-        at_p: builder.load(memory, zext_u8, one_byte, p),
+    let options = compile_options(matches, source_name)?;
+
+    // In Ook!, `!` is part of the commands.
+    let split = matches.is_present("split-on-bang");
+    if split && options.dialect != Dialect::Brainfuck {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--split-on-bang only works with Brainfuck",
+        ))?;
+    }
+    let (program, embedded_input) = if split {
+        split_on_bang(&bf)
+    } else {
+        (&bf[..], &[][..])
     };
 
-    let mut block = builder.block(Box::new([]), Box::new([]));
-    let zero_p = block.local_set(context.pointer, context.zero);
-    block.expr(zero_p);
-    context.build(&bf, &mut block, true)?;
-    let block_id = block.id();
-    drop(block);
-    let begin = From::from(block_id);
+    // Denied warnings come back as an error from compiling.
+    if !options.deny_warnings {
+        warn(matches, &warnings(program, &options), "bf2wasm");
+    }
+    if matches.is_present("check") {
+        return check(program, &options);
+    }
+
+    let wants_stats = matches.is_present("stats") || matches.is_present("verbose");
+    let (wasm, stats) = compile_cached(matches, program, &options, wants_stats)?;
+    // Standard output might be taken by the module.
+    if let Some(stats) = stats {
+        if matches.is_present("stats") {
+            if matches.value_of("message-format") == Some("json") {
+                eprintln!("{}", stats.to_json());
+            } else {
+                eprintln!("{}", stats);
+            }
+        }
+        if matches.is_present("verbose") {
+            if matches.value_of("message-format") == Some("json") {
+                eprintln!("{}", stats.optimized.to_json());
+            } else {
+                eprintln!("bf2wasm: {}", stats.optimized);
+            }
+        }
+    }
+
+    write_output(matches, &wasm)?;
+
+    if matches.is_present("emit-html") {
+        let output_path = output_file(matches, "--emit-html")?;
+        let wasm_url = match output_path.file_name().and_then(OsStr::to_str) {
+            Some(name) => name,
+            None => Err(Error::new(
+                ErrorKind::Io,
+                "output file name isn't valid UTF-8",
+            ))?,
+        };
+        let html = html_harness(wasm_url, &options);
+        fs::write(output_path.with_extension("html"), html).map_err(io_error)?;
+    }
+
+    if matches.is_present("emit-manifest") {
+        let output_path = output_file(matches, "--emit-manifest")?;
+        let manifest = manifest(&wasm)? + "\n";
+        fs::write(output_path.with_extension("json"), manifest).map_err(io_error)?;
+    }
+
+    if matches.is_present("run") {
+        let stdin = io::stdin();
+        let stdout = io::stdout();
+        // Files are buffered so a byte at a time doesn't mean a system call
+        // at a time. The standard streams already are.
+        let input: Box<dyn Read + '_> = match matches.value_of_os("run-input") {
+            Some(path) => Box::new(BufReader::new(File::open(path).map_err(io_error)?)),
+            None if split => Box::new(embedded_input),
+            None => Box::new(stdin.lock()),
+        };
+        let mut output: Box<dyn Write + '_> = match matches.value_of_os("run-output") {
+            Some(path) => Box::new(BufWriter::new(File::create(path).map_err(io_error)?)),
+            None => Box::new(stdout.lock()),
+        };
+        // The output is still written when it's checked, so a failing check
+        // can be seen for what it is.
+        let run_stats = match expected_output(program, &options) {
+            Some(expected) => {
+                let mut actual = Vec::new();
+                let run_stats = run_with_options(&wasm, &options, input, &mut actual)?;
+                output.write_all(&actual).map_err(io_error)?;
+                output.flush().map_err(io_error)?;
+                check_output(&expected, &actual)?;
+                run_stats
+            }
+            None => run_with_options(&wasm, &options, input, output)?,
+        };
+        // Standard output belongs to the program.
+        if let Some(cells) = run_stats.cells {
+            eprintln!("bf2wasm: the program used {} cells of the tape", cells);
+        }
+        for (counter, count) in &run_stats.profile {
+            eprintln!("{}: {}", counter.name(), count);
+        }
+        if matches.is_present("time") {
+            if matches.value_of("message-format") == Some("json") {
+                eprintln!("{}", run_stats.to_json());
+            } else {
+                eprintln!("bf2wasm: {}", run_stats);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The matches for the command line `args`, already parsed as `matches`,
+/// with the options in a source's `header` put in front, except for any the
+/// command line sets itself, so that they're the ones that count. The header
+/// only takes long options, so it's clear which they are.
+fn with_header(
+    matches: &ArgMatches,
+    args: &[OsString],
+    header: &[String],
+) -> Result<ArgMatches<'static>, Error> {
+    let (program, rest) = match args.split_first() {
+        Some((program, rest)) => (program.clone(), rest),
+        None => (OsString::from("bf2wasm"), args),
+    };
+    let mut merged = vec![program];
+    let mut overridden = false;
+    for arg in header {
+        if let Some(option) = arg.strip_prefix("--") {
+            let name = option.split('=').next().unwrap();
+            overridden = matches.occurrences_of(name) > 0;
+        } else if arg.starts_with('-') && arg != "-" {
+            Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("the bf2wasm header only takes long options, not {}", arg),
+            ))?;
+        }
+        if !overridden {
+            merged.push(OsString::from(arg));
+        }
+    }
+    merged.extend(rest.iter().cloned());
+    app().get_matches_from_safe(merged).map_err(arg_error)
+}
+
+/// Write `wasm` where the output should go, if anywhere, as text with
+/// `--emit-wat`.
+fn write_output(matches: &ArgMatches, wasm: &[u8]) -> Result<(), Error> {
+    if let Some(output_path) = matches.value_of_os("output") {
+        let output = if matches.is_present("emit-wat") {
+            to_wat(wasm)?.into_bytes()
+        } else {
+            wasm.to_vec()
+        };
+        if output_path == OsStr::new("-") {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            stdout.write_all(&output).map_err(io_error)?;
+            stdout.flush().map_err(io_error)?;
+        } else {
+            fs::write(output_path, &output).map_err(io_error)?;
+        }
+        if matches.is_present("summary") || matches.is_present("verbose") {
+            let name = if output_path == OsStr::new("-") {
+                String::from("standard output")
+            } else {
+                Path::new(output_path).display().to_string()
+            };
+            eprintln!(
+                "bf2wasm: wrote {}: {} bytes, hash {}",
+                name,
+                output.len(),
+                short_hash(&output)
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The file the output is going to, for `flag` to write something else next
+/// to.
+fn output_file<'a>(matches: &'a ArgMatches, flag: &str) -> Result<&'a Path, Error> {
+    match matches.value_of_os("output") {
+        Some(path) if path != OsStr::new("-") => Ok(Path::new(path)),
+        _ => Err(Error::new(
+            ErrorKind::Io,
+            format!("{} needs an output file", flag),
+        )),
+    }
+}
+
+/// Compile `bf`, or take the module from `--cache-dir` if it's there. A
+/// module from the cache comes without stats, so `wants_stats` compiles it
+/// anyway.
+fn compile_cached(
+    matches: &ArgMatches,
+    bf: &[u8],
+    options: &CompileOptions,
+    wants_stats: bool,
+) -> Result<(Vec<u8>, Option<Stats>), Error> {
+    let cache = matches
+        .value_of_os("cache-dir")
+        .map(|dir| Cache::new(Path::new(dir)));
+    if let Some(cache) = &cache {
+        if !wants_stats {
+            if let Some(wasm) = cache.get(bf, options) {
+                return Ok((wasm, None));
+            }
+        }
+    }
+    let (wasm, stats) = compile_with_stats(bf, options)?;
+    if let Some(cache) = &cache {
+        cache.put(bf, options, &wasm)?;
+    }
+    Ok((wasm, Some(stats)))
+}
 
-    let main_func = builder.finish(main_func_type, vec![], vec![begin], &mut module);
-    module.exports.add("main", main_func);
+/// The compile options the command line asks for, for the source named
+/// `source_name`. A bad value is an error rather than the end of the
+/// process, since with `--batch` or `--watch` it might only come from one
+/// source's header.
+fn compile_options(matches: &ArgMatches, source_name: String) -> Result<CompileOptions, Error> {
+    // A WASI command defines its own memory and exports `_start`. It can't
+    // run from the start function, since WASI needs the instance's memory
+    // before the instance exists.
+    let standalone = matches.is_present("standalone");
+    Ok(CompileOptions {
+        tape_size: value_t!(matches, "tape-size", u32).map_err(arg_error)?,
+        max_tape_size: if matches.is_present("max-tape-size") {
+            Some(value_t!(matches, "max-tape-size", u32).map_err(arg_error)?)
+        } else {
+            None
+        },
+        start_offset: value_t!(matches, "start-offset", u32).map_err(arg_error)?,
+        self_contained_memory: standalone || matches.is_present("self-contained-memory"),
+        grow_memory: matches.is_present("grow-memory"),
+        target: if standalone {
+            Target::Wasi
+        } else {
+            value_t!(matches, "target", Target).map_err(arg_error)?
+        },
+        backend: value_t!(matches, "backend", Backend).map_err(arg_error)?,
+        initial_data: match matches.value_of_os("initial-data") {
+            Some(path) => fs::read(path).map_err(io_error)?,
+            None => Vec::new(),
+        },
+        batch_output: matches.is_present("batch-output"),
+        input_mode: value_t!(matches, "input-mode", InputMode).map_err(arg_error)?,
+        cell_size: value_t!(matches, "cell-size", CellSize).map_err(arg_error)?,
+        signed_cells: matches.is_present("signed-cells"),
+        force_align_1: matches.is_present("force-align-1"),
+        eof: value_t!(matches, "eof", Eof).map_err(arg_error)?,
+        overflow: value_t!(matches, "cell-overflow", Overflow).map_err(arg_error)?,
+        dialect: value_t!(matches, "dialect", Dialect).map_err(arg_error)?,
+        strict: matches.is_present("strict"),
+        deny_warnings: matches.is_present("deny-warnings"),
+        max_nesting: value_t!(matches, "max-nesting", u32).map_err(arg_error)?,
+        bounds_check: matches.is_present("bounds-check"),
+        pointer_wrap: matches.is_present("pointer-wrap"),
+        sanitize: matches.is_present("sanitize"),
+        tape_in_locals: matches.is_present("tape-in-locals"),
+        strict_ascii_output: matches.is_present("strict-ascii-output"),
+        report_traps: matches.is_present("report-traps"),
+        debug_info: matches.is_present("debug-info"),
+        line_comments: matches.is_present("line-comments"),
+        dwarf: matches.value_of("debug") == Some("dwarf"),
+        source_name: source_name,
+        extensions: if matches.is_present("extension") {
+            values_t!(matches, "extension", Extension).map_err(arg_error)?
+        } else {
+            Vec::new()
+        },
+        start: matches.is_present("start"),
+        export_name: if standalone {
+            String::from("_start")
+        } else {
+            matches.value_of("export-name").unwrap().to_owned()
+        },
+        import_module: matches.value_of("import-module").unwrap().to_owned(),
+        memory_import: matches.value_of("memory-import").unwrap().to_owned(),
+        putc_import: matches.value_of("putc-import").unwrap().to_owned(),
+        getc_import: matches.value_of("getc-import").unwrap().to_owned(),
+        profile: matches.is_present("profile"),
+        max_steps: value_t!(matches, "max-steps", u64).map_err(arg_error)?,
+        producers: !matches.is_present("no-producers"),
+        main_block: !matches.is_present("no-main-block"),
+        pad_to: value_t!(matches, "pad-to", u32).map_err(arg_error)?,
+        validate_output: matches.is_present("validate") || cfg!(debug_assertions),
+        optimizations: Optimizations::level(
+            value_t!(matches, "opt-level", u32).map_err(arg_error)?,
+        ),
+        features: if matches.is_present("enable") {
+            values_t!(matches, "enable", Feature).map_err(arg_error)?
+        } else {
+            Vec::new()
+        },
+    })
+}
+
+/// Compile every `.bf` file in `dir`, in order of name, to a `.wasm` file
+/// next to it, each with the options in its own header, if it has one. A
+/// file which doesn't compile is reported, and the rest are still tried.
+fn batch(matches: &ArgMatches, args: &[OsString], dir: &Path) -> Result<(), Error> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).map_err(io_error)? {
+        let path = entry.map_err(io_error)?.path();
+        if path.extension() == Some(OsStr::new("bf")) && path.is_file() {
+            paths.push(path);
+        }
+    }
+    paths.sort();
 
-    let wasm = module.emit_wasm().context(ErrorKind::Ice)?;
-    fs::write(output_path, wasm).context(ErrorKind::Io)?;
+    let mut failed = 0;
+    for path in &paths {
+        let result = fs::read(path).map_err(io_error).and_then(|bf| {
+            let header_matches;
+            let (matches, bf) = match option_header(&bf) {
+                Some((header, blanked)) => {
+                    header_matches = with_header(matches, args, &header)?;
+                    (&header_matches, blanked)
+                }
+                None => (matches, bf),
+            };
+            let options = compile_options(matches, path.to_string_lossy().into_owned())?;
+            if !options.deny_warnings {
+                let prefix = format!("bf2wasm: {}", path.display());
+                warn(matches, &warnings(&bf, &options), &prefix);
+            }
+            let (wasm, _) = compile_cached(matches, &bf, &options, false)?;
+            fs::write(path.with_extension("wasm"), wasm).map_err(io_error)?;
+            Ok(())
+        });
+        match result {
+            Ok(()) => eprintln!("bf2wasm: {}: ok", path.display()),
+            Err(error) => {
+                report(matches, &error, &format!("bf2wasm: {}", path.display()));
+                failed += 1;
+            }
+        }
+    }
 
+    if failed > 0 {
+        let message = format!("{} of {} files failed to compile", failed, paths.len());
+        Err(Error::new(ErrorKind::InvalidInput, message))?;
+    }
     Ok(())
 }
+
+/// Compile the `--multi` programs into one module, exporting each under its
+/// name. Every program is checked before any is compiled, so they're all
+/// reported at once.
+///
+/// The programs share a module, so they're compiled with the same options,
+/// and a program with options of its own in a header is an error.
+fn multi(matches: &ArgMatches) -> Result<(), Error> {
+    let mut programs = Vec::new();
+    for program in matches.values_of("multi").unwrap() {
+        let (name, path) = match program.find('=') {
+            Some(equals) => (&program[..equals], &program[equals + 1..]),
+            None => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("--multi needs NAME=FILE.bf, not {}", program),
+            ))?,
+        };
+        programs.push((name, path, fs::read(path).map_err(io_error)?));
+    }
+    let options = compile_options(matches, programs[0].1.to_owned())?;
+
+    let mut failed = 0;
+    for (_, path, bf) in &programs {
+        let prefix = format!("bf2wasm: {}", path);
+        if option_header(bf).is_some() {
+            let error = Error::new(
+                ErrorKind::InvalidInput,
+                "--multi can't take options from a bf2wasm header",
+            );
+            report(matches, &error, &prefix);
+            failed += 1;
+            continue;
+        }
+        if !options.deny_warnings {
+            warn(matches, &warnings(bf, &options), &prefix);
+        }
+        if let Err(error) = check(bf, &options) {
+            report(matches, &error, &prefix);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        let message = format!(
+            "{} of {} programs failed to compile",
+            failed,
+            programs.len()
+        );
+        Err(Error::new(ErrorKind::InvalidInput, message))?;
+    }
+
+    let programs: Vec<(&str, &[u8])> = programs
+        .iter()
+        .map(|(name, _, bf)| (*name, &bf[..]))
+        .collect();
+    let wasm = compile_programs(&programs, &options)?;
+    write_output(matches, &wasm)
+}
@@ -0,0 +1,99 @@
+use crate::json::json_string;
+use crate::{Error, ErrorKind, PAGE_SIZE};
+use walrus::{ExportItem, FunctionId, ImportKind, Module, ValType};
+
+fn val_type_name(ty: ValType) -> &'static str {
+    match ty {
+        ValType::I32 => "i32",
+        ValType::I64 => "i64",
+        ValType::F32 => "f32",
+        ValType::F64 => "f64",
+        _ => "unknown",
+    }
+}
+
+fn val_types_json(types: &[ValType]) -> String {
+    let names: Vec<String> = types
+        .iter()
+        .map(|&ty| json_string(val_type_name(ty)))
+        .collect();
+    format!("[{}]", names.join(","))
+}
+
+/// The kind of a function and its signature, as JSON fields.
+fn function_json(module: &Module, func: FunctionId) -> String {
+    let ty = module.types.get(module.funcs.get(func).ty());
+    format!(
+        "\"kind\":\"function\",\"params\":{},\"results\":{}",
+        val_types_json(ty.params()),
+        val_types_json(ty.results())
+    )
+}
+
+fn limits_json(initial: u32, maximum: Option<u32>) -> String {
+    let bytes = |pages: u32| (u64::from(pages) * u64::from(PAGE_SIZE)).to_string();
+    format!(
+        "\"min_bytes\":{},\"max_bytes\":{}",
+        bytes(initial),
+        maximum.map_or(String::from("null"), bytes)
+    )
+}
+
+/// Describe a compiled module's imports and exports, with the signatures of
+/// its functions and the size of its memory, as a line of JSON, so that a
+/// host can wire itself up to the module without knowing how it was
+/// compiled.
+///
+/// It's read back from the module itself, so it can't disagree with it.
+pub fn manifest(wasm: &[u8]) -> Result<String, Error> {
+    let module =
+        Module::from_buffer(wasm).map_err(|error| Error::new(ErrorKind::Ice, error.to_string()))?;
+
+    let mut imports = Vec::new();
+    for import in module.imports.iter() {
+        let item = match import.kind {
+            ImportKind::Function(func) => function_json(&module, func),
+            ImportKind::Memory(memory) => {
+                let memory = module.memories.get(memory);
+                format!(
+                    "\"kind\":\"memory\",{}",
+                    limits_json(memory.initial, memory.maximum)
+                )
+            }
+            _ => String::from("\"kind\":\"other\""),
+        };
+        imports.push(format!(
+            "{{\"module\":{},\"name\":{},{}}}",
+            json_string(&import.module),
+            json_string(&import.name),
+            item
+        ));
+    }
+
+    let mut exports = Vec::new();
+    for export in module.exports.iter() {
+        let item = match export.item {
+            ExportItem::Function(func) => function_json(&module, func),
+            ExportItem::Memory(_) => String::from("\"kind\":\"memory\""),
+            _ => String::from("\"kind\":\"other\""),
+        };
+        exports.push(format!(
+            "{{\"name\":{},{}}}",
+            json_string(&export.name),
+            item
+        ));
+    }
+
+    // Programs only ever have the one memory.
+    let memory = match module.memories.iter().next() {
+        Some(memory) => format!("{{{}}}", limits_json(memory.initial, memory.maximum)),
+        None => String::from("null"),
+    };
+
+    Ok(format!(
+        "{{\"imports\":[{}],\"exports\":[{}],\"memory\":{}}}",
+        imports.join(","),
+        exports.join(","),
+        memory
+    ))
+}
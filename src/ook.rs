@@ -0,0 +1,82 @@
+use crate::position::bom_len;
+use crate::{Diagnostic, DiagnosticKind, Position};
+
+/// The Brainfuck command for each pair of Ook! tokens, by their punctuation.
+fn command(first: u8, second: u8) -> Option<u8> {
+    match (first, second) {
+        (b'.', b'?') => Some(b'>'),
+        (b'?', b'.') => Some(b'<'),
+        (b'.', b'.') => Some(b'+'),
+        (b'!', b'!') => Some(b'-'),
+        (b'!', b'.') => Some(b'.'),
+        (b'.', b'!') => Some(b','),
+        (b'!', b'?') => Some(b'['),
+        (b'?', b'!') => Some(b']'),
+        _ => None,
+    }
+}
+
+/// The punctuation of the Ook! token at the start of `source`, if there is
+/// one.
+fn token(source: &[u8]) -> Option<u8> {
+    if !source.starts_with(b"Ook") {
+        return None;
+    }
+    match source.get(3) {
+        Some(&punctuation) if b".?!".contains(&punctuation) => Some(punctuation),
+        _ => None,
+    }
+}
+
+/// Translate Ook! source to Brainfuck, along with any problems found in it.
+///
+/// The translation has the same layout as the source: each command is at
+/// the offset of its first token, line breaks are kept, and everything else
+/// becomes a space. That way, offsets into it are offsets into the source,
+/// and the backend doesn't need to know which dialect it came from.
+///
+/// Bytes which aren't part of a token are comments, unless `strict` is set,
+/// in which case anything other than whitespace or a leading byte order mark
+/// is reported.
+pub(crate) fn translate(ook: &[u8], strict: bool) -> (Vec<u8>, Vec<Diagnostic>) {
+    let mut bf: Vec<u8> = ook
+        .iter()
+        .map(|&byte| if byte == b'\n' { b'\n' } else { b' ' })
+        .collect();
+    let mut diagnostics = Vec::new();
+    let mut report = |kind, offset| {
+        diagnostics.push(Diagnostic {
+            kind: kind,
+            position: Position::locate(ook, offset),
+        })
+    };
+
+    let mut tokens = Vec::new();
+    let mut offset = bom_len(ook);
+    while offset < ook.len() {
+        if let Some(punctuation) = token(&ook[offset..]) {
+            tokens.push((offset, punctuation));
+            offset += 4;
+            continue;
+        }
+        let byte = ook[offset];
+        if strict && !byte.is_ascii_whitespace() {
+            report(DiagnosticKind::InvalidCharacter(byte), offset);
+        }
+        offset += 1;
+    }
+
+    for pair in tokens.chunks(2) {
+        let (offset, first) = pair[0];
+        match pair.get(1) {
+            Some(&(_, second)) => match command(first, second) {
+                Some(command) => bf[offset] = command,
+                None => report(DiagnosticKind::InvalidOokCommand, offset),
+            },
+            None => report(DiagnosticKind::DanglingOok, offset),
+        }
+    }
+
+    diagnostics.sort_by_key(|diagnostic| diagnostic.position.offset);
+    (bf, diagnostics)
+}
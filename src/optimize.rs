@@ -0,0 +1,682 @@
+use crate::ir::{Instr, Op};
+use crate::{Optimizations, Optimized, Overflow};
+use std::collections::HashMap;
+
+/// Cancel adds and moves which undo each other, such as `+-` or `>+-<`,
+/// until none are left.
+///
+/// Parsing already folds each run of `+`/`-` or `>`/`<`, so this only has to
+/// drop the runs that net to nothing and fold whatever they separated. Adds
+/// only cancel when cells wrap, since `+-` at the limit of a saturating or
+/// trapping cell isn't a no-op. Wrapping adds of a whole multiple of the
+/// cell's range, such as 256 `+`s for 8-bit cells, are no-ops too.
+///
+/// `max` is the largest value a cell can hold.
+pub(crate) fn cancel_inverses(
+    instrs: &[Instr],
+    overflow: Overflow,
+    max: u32,
+    optimized: &mut Optimized,
+) -> Vec<Instr> {
+    let wraps_to_zero = |delta: i32| overflow == Overflow::Wrap && delta as u32 & max == 0;
+    let mut cancelled: Vec<Instr> = Vec::with_capacity(instrs.len());
+    for &instr in instrs {
+        let last = cancelled.last_mut().map(|last| &mut last.op);
+        let gone = match (instr.op, last) {
+            (Op::Add(0), _) | (Op::Move(0), _) => {
+                optimized.cancelled_runs += 1;
+                continue;
+            }
+            (Op::Add(delta), _) if wraps_to_zero(delta) => {
+                optimized.cancelled_runs += 1;
+                continue;
+            }
+            (Op::Add(delta), Some(Op::Add(n))) if overflow == Overflow::Wrap => {
+                *n = n.wrapping_add(delta);
+                wraps_to_zero(*n)
+            }
+            (Op::Move(offset), Some(Op::Move(n))) => {
+                *n = n.wrapping_add(offset);
+                *n == 0
+            }
+            _ => {
+                cancelled.push(instr);
+                continue;
+            }
+        };
+        if gone {
+            cancelled.pop();
+            optimized.cancelled_runs += 1;
+        }
+    }
+    cancelled
+}
+
+/// Replace loops with straight-line code wherever the loop's effect can be
+/// computed up front.
+///
+/// The replacement for a loop is attributed to the loop's `[`.
+pub(crate) fn lower_loops(
+    instrs: &[Instr],
+    overflow: Overflow,
+    optimizations: &Optimizations,
+    optimized: &mut Optimized,
+) -> Vec<Instr> {
+    let mut lowered = Vec::with_capacity(instrs.len());
+    let mut i = 0;
+    while i < instrs.len() {
+        if instrs[i].op == Op::Open {
+            let body = &instrs[i + 1..];
+            let mut replacement = None;
+            if optimizations.lower_loops {
+                replacement = linear_loop(body, overflow);
+            }
+            if optimizations.scan_loops && replacement.is_none() {
+                replacement = scan_loop(body);
+            }
+            if let Some((len, replacement)) = replacement {
+                match replacement[0] {
+                    Op::Clear => optimized.clear_loops += 1,
+                    Op::Scan(_) => optimized.scan_loops += 1,
+                    _ => optimized.multiply_loops += 1,
+                }
+                let source = instrs[i].source;
+                lowered.extend(replacement.into_iter().map(|op| Instr::new(op, source)));
+                i += 1 + len;
+                continue;
+            }
+        }
+        lowered.push(instrs[i]);
+        i += 1;
+    }
+    lowered
+}
+
+/// Recognize a loop whose body only adds constants to cells and returns the
+/// pointer to where it started, such as `[-]`, `[->++<]` or `[->>>+<<<]`.
+/// It adds the counter times each cell's net change per iteration to the
+/// cell, however far away it is and however many times the body visits it.
+///
+/// `body` starts just after the loop's `Open`. On success, returns the number
+/// of ops consumed, including the `Close`, and the straight-line equivalent.
+///
+/// Only `[-]` survives saturating or trapping arithmetic: anything else
+/// depends on the cells wrapping.
+fn linear_loop(body: &[Instr], overflow: Overflow) -> Option<(usize, Vec<Op>)> {
+    let mut offset = 0i32;
+    // Kept in the order cells are first touched, so the output is stable.
+    let mut deltas: Vec<(i32, i32)> = Vec::new();
+    for (i, instr) in body.iter().enumerate() {
+        match instr.op {
+            Op::Add(n) => match deltas.iter_mut().find(|(o, _)| *o == offset) {
+                Some((_, delta)) => *delta = delta.wrapping_add(n),
+                None => deltas.push((offset, n)),
+            },
+            Op::Move(n) => offset = offset.checked_add(n)?,
+            Op::Close => {
+                if offset != 0 {
+                    return None;
+                }
+                let counter = deltas
+                    .iter()
+                    .find(|(o, _)| *o == 0)
+                    .map_or(0, |&(_, delta)| delta);
+                let mut lowered: Vec<Op> = deltas
+                    .iter()
+                    .filter(|&&(o, delta)| o != 0 && delta != 0)
+                    .map(|&(offset, factor)| Op::MulAdd { offset, factor })
+                    .collect();
+
+                // Stepping the counter by one in either direction reaches zero
+                // eventually. Counting down runs the body once per unit of its
+                // value, and counting up runs it once per unit of its
+                // negation, which is the same thing as far as wrapping cells
+                // are concerned.
+                match (counter, overflow) {
+                    (-1, Overflow::Wrap) => {}
+                    (-1, _) if lowered.is_empty() => {}
+                    (1, Overflow::Wrap) => {
+                        for op in &mut lowered {
+                            if let Op::MulAdd { factor, .. } = op {
+                                *factor = factor.wrapping_neg();
+                            }
+                        }
+                    }
+                    _ => return None,
+                }
+                lowered.push(Op::Clear);
+                return Some((i + 1, lowered));
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Recognize a loop which only moves the pointer, such as `[>]` or `[<<]`,
+/// in the same form as `linear_loop`.
+fn scan_loop(body: &[Instr]) -> Option<(usize, Vec<Op>)> {
+    match (body.get(0)?.op, body.get(1)?.op) {
+        (Op::Move(step), Op::Close) if step != 0 && step != i32::min_value() => {
+            Some((2, vec![Op::Scan(step)]))
+        }
+        _ => None,
+    }
+}
+
+/// The value of a cell holding `value` after adding `delta`, if it can be
+/// known without running the program.
+fn add_to_constant(value: i32, delta: i32, overflow: Overflow, max: u32) -> Option<i32> {
+    let max = i64::from(max);
+    let sum = i64::from(value) + i64::from(delta);
+    match overflow {
+        Overflow::Wrap => Some(value.wrapping_add(delta)),
+        Overflow::Saturate => Some(sum.max(0).min(max) as i32),
+        // Leave the add in place to trap when the program runs.
+        Overflow::Trap if sum < 0 || sum > max => None,
+        Overflow::Trap => Some(sum as i32),
+    }
+}
+
+/// Drop loops which follow straight after a loop or a clear, as in
+/// `[-][>+<-]`. A loop only ends once its cell is zero, so if nothing
+/// happens in between, the next loop can never run.
+pub(crate) fn drop_dead_loops(instrs: &[Instr], optimized: &mut Optimized) -> Vec<Instr> {
+    let mut live: Vec<Instr> = Vec::with_capacity(instrs.len());
+    let mut dead_depth = 0;
+    for &instr in instrs {
+        if dead_depth > 0 {
+            match instr.op {
+                Op::Open => dead_depth += 1,
+                Op::Close => dead_depth -= 1,
+                _ => {}
+            }
+            continue;
+        }
+        let zero = match live.last().map(|last| last.op) {
+            Some(Op::Close) | Some(Op::Scan(_)) | Some(Op::Clear) => true,
+            _ => false,
+        };
+        if instr.op == Op::Open && zero {
+            dead_depth = 1;
+            optimized.dead_loops += 1;
+            continue;
+        }
+        live.push(instr);
+    }
+    live
+}
+
+/// Fold a clear of a cell followed by a lowered loop adding to it, such as
+/// `>[-]<[->+<]`, into a plain assignment, since adding to zero is the same
+/// as setting. Runs after `lower_loops`.
+pub(crate) fn assign_copies(instrs: &[Instr], optimized: &mut Optimized) -> Vec<Instr> {
+    let op_at = |i: usize| instrs.get(i).map(|instr| instr.op);
+    let mut assigned = Vec::with_capacity(instrs.len());
+    let mut i = 0;
+    while i < instrs.len() {
+        if let (Some(Op::Move(there)), Some(Op::Clear), Some(Op::Move(back))) =
+            (op_at(i), op_at(i + 1), op_at(i + 2))
+        {
+            let mut end = i + 3;
+            while let Some(Op::MulAdd { .. }) = op_at(end) {
+                end += 1;
+            }
+            let adds_there = instrs[i + 3..end].iter().any(|instr| match instr.op {
+                Op::MulAdd { offset, .. } => offset == there,
+                _ => false,
+            });
+            let lowered = op_at(end) == Some(Op::Clear);
+            if adds_there && lowered && there != 0 && back == there.wrapping_neg() {
+                for &instr in &instrs[i + 3..=end] {
+                    let op = match instr.op {
+                        Op::MulAdd { offset, factor } if offset == there => {
+                            Op::MulSet { offset, factor }
+                        }
+                        op => op,
+                    };
+                    assigned.push(Instr::new(op, instr.source));
+                }
+                optimized.assignments += 1;
+                i = end + 1;
+                continue;
+            }
+        }
+        assigned.push(instrs[i]);
+        i += 1;
+    }
+    assigned
+}
+
+/// Fold clears followed by adds into a store of the sum, so `[-]+++++`
+/// becomes a single store of 5.
+///
+/// `max` is the largest value a cell can hold.
+pub(crate) fn fold_sets(
+    instrs: &[Instr],
+    overflow: Overflow,
+    max: u32,
+    optimized: &mut Optimized,
+) -> Vec<Instr> {
+    let mut folded: Vec<Instr> = Vec::with_capacity(instrs.len());
+    for &instr in instrs {
+        if let Op::Add(delta) = instr.op {
+            if let Some(last) = folded.last_mut() {
+                let value = match last.op {
+                    Op::Clear => Some(0),
+                    Op::Set(value) => Some(value),
+                    _ => None,
+                };
+                let sum = value.and_then(|value| add_to_constant(value, delta, overflow, max));
+                if let Some(sum) = sum {
+                    last.op = Op::Set(sum);
+                    optimized.sets += 1;
+                    continue;
+                }
+            }
+        }
+        folded.push(instr);
+    }
+    folded
+}
+
+/// The most times `unroll_loops` will copy a loop's body.
+const UNROLL_MAX_TRIPS: u32 = 8;
+
+/// The most ops `unroll_loops` will replace a loop with.
+const UNROLL_MAX_OPS: usize = 64;
+
+/// Unroll loops which run a known, small number of times, such as the loop
+/// in `++++[.>+<-]`, into that many copies of their body. Runs after
+/// `fold_sets`, which establishes the counter's value.
+///
+/// `max` is the largest value a cell can hold.
+pub(crate) fn unroll_loops(
+    instrs: &[Instr],
+    overflow: Overflow,
+    max: u32,
+    optimized: &mut Optimized,
+) -> Vec<Instr> {
+    let mut unrolled: Vec<Instr> = Vec::with_capacity(instrs.len());
+    let mut i = 0;
+    while i < instrs.len() {
+        let trips = match (unrolled.last().map(|last| last.op), instrs[i].op) {
+            (Some(Op::Set(value)), Op::Open) => value as u32 & max,
+            _ => {
+                unrolled.push(instrs[i]);
+                i += 1;
+                continue;
+            }
+        };
+        let body = &instrs[i + 1..];
+        match counted_loop(body, overflow) {
+            Some(len) if trips <= UNROLL_MAX_TRIPS && trips as usize * len <= UNROLL_MAX_OPS => {
+                for _ in 0..trips {
+                    unrolled.extend_from_slice(&body[..len]);
+                }
+                optimized.unrolled_loops += 1;
+                // Skip the body and the `Close`.
+                i += len + 2;
+            }
+            _ => {
+                unrolled.push(instrs[i]);
+                i += 1;
+            }
+        }
+    }
+    unrolled
+}
+
+/// Recognize a loop body which takes exactly one from the counter each time
+/// around, leaves it alone otherwise, and returns the pointer to it, so the
+/// loop runs as many times as the counter's value on entry. Nested loops
+/// could do anything, so they're left alone.
+///
+/// `body` starts just after the loop's `Open`. On success, returns the number
+/// of ops in the body, not including the `Close`.
+fn counted_loop(body: &[Instr], overflow: Overflow) -> Option<usize> {
+    let mut offset = 0i32;
+    let mut adds = 0;
+    let mut delta = 0i32;
+    for (len, instr) in body.iter().enumerate() {
+        match instr.op {
+            Op::Add(n) if offset == 0 => {
+                adds += 1;
+                delta = delta.wrapping_add(n);
+            }
+            Op::Add(_) | Op::Output | Op::Dump | Op::Stream => {}
+            Op::Input | Op::Clear | Op::Set(_) if offset != 0 => {}
+            Op::MulAdd { offset: to, .. } | Op::MulSet { offset: to, .. }
+                if offset.wrapping_add(to) != 0 => {}
+            Op::Move(n) => offset = offset.wrapping_add(n),
+            // Clamping or trapping part way through an iteration would make
+            // the count depend on more than the counter's value.
+            Op::Close
+                if offset == 0 && delta == -1 && (adds == 1 || overflow == Overflow::Wrap) =>
+            {
+                return Some(len)
+            }
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Fold runs of clears of neighbouring cells, such as `[-]>[-]>[-]`, into a
+/// single fill followed by the moves they made.
+///
+/// A lone clear is a single store, which is no bigger than a fill and
+/// quicker, so it's left alone.
+pub(crate) fn fill_clears(instrs: &[Instr], optimized: &mut Optimized) -> Vec<Instr> {
+    let op_at = |i: usize| instrs.get(i).map(|instr| instr.op);
+    let mut filled = Vec::with_capacity(instrs.len());
+    let mut i = 0;
+    while i < instrs.len() {
+        let instr = instrs[i];
+        i += 1;
+        let step = match (instr.op, op_at(i)) {
+            (Op::Clear, Some(Op::Move(step))) if step == 1 || step == -1 => step,
+            _ => {
+                filled.push(instr);
+                continue;
+            }
+        };
+        let move_source = instrs[i].source;
+        let mut cells = 1;
+        while op_at(i) == Some(Op::Move(step)) && op_at(i + 1) == Some(Op::Clear) {
+            cells += 1;
+            i += 2;
+        }
+        if cells == 1 {
+            filled.push(instr);
+        } else {
+            filled.push(Instr::new(Op::Fill(cells * step), instr.source));
+            optimized.fills += 1;
+            filled.push(Instr::new(Op::Move((cells - 1) * step), move_source));
+        }
+    }
+    filled
+}
+
+/// The most ops `precompute_output` will run at compile time.
+const PRECOMPUTE_MAX_STEPS: usize = 1_000_000;
+
+/// The most output `precompute_output` will put in the module.
+const PRECOMPUTE_MAX_OUTPUT: usize = 64 * 1024;
+
+/// Where running the start of a program at compile time had got to at the
+/// start of an op outside every loop, to go back to if what comes next turns
+/// out to need running for real.
+#[derive(Copy, Clone)]
+struct Checkpoint {
+    pc: usize,
+    pointer: i64,
+    output_len: usize,
+}
+
+/// Run as much of the start of a program as doesn't depend on its input at
+/// compile time, and replace it with writing the output it would have
+/// written all at once, followed by sets leaving the tape as it would have
+/// been. Returns the new program and the output, if any ran. Runs after
+/// `lower_loops`, so there's less to run.
+///
+/// Running stops at the first `,`, or anything else which has to happen at
+/// runtime, and anything which leaves the cells from `first_cell` up to
+/// `end_cell`, relative to where the pointer starts, since the module has
+/// to trap or not for that itself. A loop it stops in never started, as
+/// far as the program is concerned, so a program which is one big loop
+/// doesn't get anywhere. Cells have to wrap, and start out zero.
+///
+/// `max` is the largest value a cell can hold.
+pub(crate) fn precompute_output(
+    instrs: &[Instr],
+    max: u32,
+    first_cell: i64,
+    end_cell: i64,
+    optimized: &mut Optimized,
+) -> Option<(Vec<Instr>, Vec<u8>)> {
+    let mut matches = vec![0; instrs.len()];
+    let mut opens = Vec::new();
+    for (i, instr) in instrs.iter().enumerate() {
+        match instr.op {
+            Op::Open => opens.push(i),
+            Op::Close => {
+                let open = opens.pop()?;
+                matches[open] = i;
+                matches[i] = open;
+            }
+            _ => {}
+        }
+    }
+
+    let mut cells: HashMap<i64, u32> = HashMap::new();
+    let mut pointer = 0i64;
+    let mut output = Vec::new();
+    let mut depth = 0;
+    let mut pc = 0;
+    let mut checkpoint = Checkpoint {
+        pc: 0,
+        pointer: 0,
+        output_len: 0,
+    };
+    // The old value of every cell changed since the checkpoint.
+    let mut undo: Vec<(i64, u32)> = Vec::new();
+    let on_tape = |cell: i64| cell >= first_cell && cell < end_cell;
+    for _ in 0..PRECOMPUTE_MAX_STEPS {
+        if depth == 0 {
+            checkpoint = Checkpoint {
+                pc: pc,
+                pointer: pointer,
+                output_len: output.len(),
+            };
+            undo.clear();
+        }
+        if pc == instrs.len() || output.len() > PRECOMPUTE_MAX_OUTPUT {
+            break;
+        }
+        let value = *cells.get(&pointer).unwrap_or(&0);
+        let mut set = |cells: &mut HashMap<i64, u32>, cell: i64, value: u32| {
+            undo.push((cell, *cells.get(&cell).unwrap_or(&0)));
+            cells.insert(cell, value & max);
+        };
+        let ran = match instrs[pc].op {
+            Op::Add(delta) => {
+                set(&mut cells, pointer, value.wrapping_add(delta as u32));
+                true
+            }
+            Op::Move(offset) => {
+                pointer += i64::from(offset);
+                on_tape(pointer)
+            }
+            Op::Output => {
+                output.push(value as u8);
+                true
+            }
+            Op::Open if value == 0 => {
+                pc = matches[pc];
+                true
+            }
+            Op::Open => {
+                depth += 1;
+                true
+            }
+            Op::Close if value != 0 => {
+                pc = matches[pc];
+                true
+            }
+            Op::Close => {
+                depth -= 1;
+                true
+            }
+            Op::Clear => {
+                set(&mut cells, pointer, 0);
+                true
+            }
+            Op::Set(value) => {
+                set(&mut cells, pointer, value as u32);
+                true
+            }
+            Op::Fill(len) => {
+                let (from, to) = if len > 0 {
+                    (pointer, pointer + i64::from(len) - 1)
+                } else {
+                    (pointer + i64::from(len) + 1, pointer)
+                };
+                if on_tape(from) && on_tape(to) {
+                    for cell in from..=to {
+                        set(&mut cells, cell, 0);
+                    }
+                    true
+                } else {
+                    false
+                }
+            }
+            Op::Scan(step) => {
+                while *cells.get(&pointer).unwrap_or(&0) != 0 && on_tape(pointer) {
+                    pointer += i64::from(step);
+                }
+                on_tape(pointer)
+            }
+            Op::MulAdd { offset, factor } | Op::MulSet { offset, factor } => {
+                let target = pointer + i64::from(offset);
+                let product = value.wrapping_mul(factor as u32);
+                let sum = match instrs[pc].op {
+                    Op::MulAdd { .. } => cells.get(&target).unwrap_or(&0).wrapping_add(product),
+                    _ => product,
+                };
+                set(&mut cells, target, sum);
+                on_tape(target)
+            }
+            _ => false,
+        };
+        if !ran {
+            break;
+        }
+        pc += 1;
+    }
+
+    // Whatever didn't finish has to run again for real.
+    for (cell, value) in undo.into_iter().rev() {
+        cells.insert(cell, value);
+    }
+    output.truncate(checkpoint.output_len);
+    if output.is_empty() {
+        return None;
+    }
+
+    let source = instrs[0].source;
+    let mut precomputed = vec![Instr::new(Op::Print(output.len() as u32), source)];
+    let mut cells: Vec<(i64, u32)> = cells.into_iter().filter(|&(_, value)| value != 0).collect();
+    cells.sort();
+    let mut at = 0;
+    let mut move_to = |precomputed: &mut Vec<Instr>, cell: i64| {
+        if cell != at {
+            precomputed.push(Instr::new(Op::Move((cell - at) as i32), source));
+            at = cell;
+        }
+    };
+    for (cell, value) in cells {
+        move_to(&mut precomputed, cell);
+        precomputed.push(Instr::new(Op::Set(value as i32), source));
+    }
+    move_to(&mut precomputed, checkpoint.pointer);
+    optimized.precomputed_ops += checkpoint.pc;
+    precomputed.extend_from_slice(&instrs[checkpoint.pc..]);
+    Some((precomputed, output))
+}
+
+/// The first and last cells, relative to where it starts, that the pointer
+/// can ever reach, if that's known without running the program.
+///
+/// That's the case when every loop leaves the pointer where it found it, as
+/// in `[->+<]`, so that every op works on the same cell each time it runs.
+/// A loop like `[>]` which moves the pointer, or a scan it's been lowered to,
+/// could reach any cell. Fills and `#` need the cells in memory, so
+/// programs with them are left alone too.
+pub(crate) fn static_cells(instrs: &[Instr]) -> Option<(i32, i32)> {
+    let mut position = 0i32;
+    let mut first = 0;
+    let mut last = 0;
+    let mut opens = Vec::new();
+    for instr in instrs {
+        let at = match instr.op {
+            Op::Move(n) => {
+                position = position.checked_add(n)?;
+                position
+            }
+            Op::Open => {
+                opens.push(position);
+                position
+            }
+            Op::Close => {
+                if opens.pop() != Some(position) {
+                    return None;
+                }
+                position
+            }
+            Op::MulAdd { offset, .. } | Op::MulSet { offset, .. } => {
+                position.checked_add(offset)?
+            }
+            Op::Scan(_) | Op::Fill(_) | Op::Dump => return None,
+            _ => position,
+        };
+        first = first.min(at);
+        last = last.max(at);
+    }
+    Some((first, last))
+}
+
+/// Whether `op` can be moved across without anybody noticing, so that the
+/// output around it can be written together.
+fn batchable(op: Op, can_trap: bool) -> bool {
+    match op {
+        Op::Output | Op::Clear | Op::Set(_) => true,
+        Op::Add(_) | Op::Move(_) | Op::MulAdd { .. } | Op::MulSet { .. } | Op::Fill(_) => !can_trap,
+        _ => false,
+    }
+}
+
+/// Stage the output of each straight-line run containing more than one `.`,
+/// writing it all at once after the run's last `.`.
+///
+/// If the run might trap part way through, only `.` and clears are batched
+/// together so that no output which would have been written is lost.
+pub(crate) fn batch_output(
+    instrs: &[Instr],
+    capacity: u32,
+    can_trap: bool,
+    optimized: &mut Optimized,
+) -> Vec<Instr> {
+    let mut batched = Vec::with_capacity(instrs.len());
+    let mut start = 0;
+    while start < instrs.len() {
+        let end = instrs[start..]
+            .iter()
+            .position(|instr| !batchable(instr.op, can_trap))
+            .map_or(instrs.len(), |len| start + len);
+        let run = &instrs[start..end];
+        let mut remaining = run.iter().filter(|instr| instr.op == Op::Output).count();
+        if remaining < 2 {
+            batched.extend_from_slice(run);
+        } else {
+            let mut staged = 0;
+            for &instr in run {
+                if instr.op != Op::Output {
+                    batched.push(instr);
+                    continue;
+                }
+                batched.push(Instr::new(Op::Stage(staged), instr.source));
+                staged += 1;
+                remaining -= 1;
+                if staged == capacity || remaining == 0 {
+                    batched.push(Instr::new(Op::Flush(staged), instr.source));
+                    optimized.batches += 1;
+                    staged = 0;
+                }
+            }
+        }
+        batched.extend(instrs.get(end));
+        start = end + 1;
+    }
+    batched
+}
@@ -0,0 +1,667 @@
+use std::str::FromStr;
+use walrus::ir::{ExtendedLoad, LoadKind, MemArg, StoreKind};
+
+/// The size of a WebAssembly memory page, in bytes.
+pub const PAGE_SIZE: u32 = 64 * 1024;
+
+/// The width of a single cell on the tape.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum CellSize {
+    /// Each cell is a single byte.
+    Bits8,
+
+    /// Each cell is two bytes.
+    Bits16,
+
+    /// Each cell is four bytes.
+    Bits32,
+}
+
+impl CellSize {
+    /// The number of bytes of memory each cell occupies.
+    pub fn bytes(self) -> u32 {
+        match self {
+            CellSize::Bits8 => 1,
+            CellSize::Bits16 => 2,
+            CellSize::Bits32 => 4,
+        }
+    }
+
+    /// The largest value a cell can hold.
+    pub fn max_value(self) -> u32 {
+        match self {
+            CellSize::Bits8 => 0xff,
+            CellSize::Bits16 => 0xffff,
+            CellSize::Bits32 => 0xffff_ffff,
+        }
+    }
+
+    pub(crate) fn load_kind(self, signed: bool) -> LoadKind {
+        let kind = if signed {
+            ExtendedLoad::SignExtend
+        } else {
+            ExtendedLoad::ZeroExtend
+        };
+        match self {
+            CellSize::Bits8 => LoadKind::I32_8 { kind: kind },
+            CellSize::Bits16 => LoadKind::I32_16 { kind: kind },
+            CellSize::Bits32 => LoadKind::I32 { atomic: false },
+        }
+    }
+
+    pub(crate) fn store_kind(self) -> StoreKind {
+        match self {
+            CellSize::Bits8 => StoreKind::I32_8 { atomic: false },
+            CellSize::Bits16 => StoreKind::I32_16 { atomic: false },
+            CellSize::Bits32 => StoreKind::I32 { atomic: false },
+        }
+    }
+
+    pub(crate) fn mem_arg(self) -> MemArg {
+        MemArg {
+            align: self.bytes(),
+            offset: 0,
+        }
+    }
+}
+
+impl FromStr for CellSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CellSize, String> {
+        match s {
+            "8" => Ok(CellSize::Bits8),
+            "16" => Ok(CellSize::Bits16),
+            "32" => Ok(CellSize::Bits32),
+            _ => Err(format!("invalid cell size '{}'", s)),
+        }
+    }
+}
+
+/// What the `,` command does at the end of input.
+///
+/// The `getc` import is expected to return -1 once input is exhausted; any
+/// other value is stored in the current cell as usual.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Eof {
+    /// Store whatever `getc` returns, end of input or not.
+    Passthrough,
+
+    /// Store zero.
+    Zero,
+
+    /// Store -1, truncated to the width of the cell.
+    MinusOne,
+
+    /// Leave the current cell as it was.
+    Unchanged,
+}
+
+impl FromStr for Eof {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Eof, String> {
+        match s {
+            "passthrough" => Ok(Eof::Passthrough),
+            "zero" => Ok(Eof::Zero),
+            "minus-one" => Ok(Eof::MinusOne),
+            "unchanged" => Ok(Eof::Unchanged),
+            _ => Err(format!("invalid EOF behavior '{}'", s)),
+        }
+    }
+}
+
+/// What happens when cell arithmetic leaves the range of a cell.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Overflow {
+    /// Wrap around modulo the cell size.
+    Wrap,
+
+    /// Clamp to the range of the cell.
+    Saturate,
+
+    /// Trap as soon as a cell leaves its range.
+    Trap,
+}
+
+impl FromStr for Overflow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Overflow, String> {
+        match s {
+            "wrap" => Ok(Overflow::Wrap),
+            "saturate" => Ok(Overflow::Saturate),
+            "trap" => Ok(Overflow::Trap),
+            _ => Err(format!("invalid overflow behavior '{}'", s)),
+        }
+    }
+}
+
+/// The host interface the compiled module uses for input and output.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Target {
+    /// Import `env.putc` and `env.getc`.
+    Env,
+
+    /// Import `fd_write` and `fd_read` from `wasi_snapshot_preview1`. WASI
+    /// hosts read from the module's own memory, so this implies
+    /// `self_contained_memory`.
+    Wasi,
+}
+
+/// How the compiled module runs the program.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Backend {
+    /// Compile every op to WebAssembly of its own. This is the fastest, but
+    /// the module grows with the program.
+    Compiled,
+
+    /// Encode the ops as bytecode in a data segment, and run them with an
+    /// interpreter loop. The module grows by a few bytes per op rather than
+    /// dozens, for enormous programs, but dispatching each op at runtime is
+    /// several times slower.
+    Interpreter,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Backend, String> {
+        match s {
+            "compiled" => Ok(Backend::Compiled),
+            "interpreter" => Ok(Backend::Interpreter),
+            _ => Err(format!("invalid backend '{}'", s)),
+        }
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Target, String> {
+        match s {
+            "env" => Ok(Target::Env),
+            "wasi" => Ok(Target::Wasi),
+            _ => Err(format!("invalid target '{}'", s)),
+        }
+    }
+}
+
+/// The language the source is written in.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Dialect {
+    /// Plain Brainfuck.
+    Brainfuck,
+
+    /// Ook!, which spells each command as a pair of `Ook.`, `Ook?` and
+    /// `Ook!` tokens.
+    Ook,
+}
+
+impl FromStr for Dialect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Dialect, String> {
+        match s {
+            "brainfuck" => Ok(Dialect::Brainfuck),
+            "ook" => Ok(Dialect::Ook),
+            _ => Err(format!("invalid dialect '{}'", s)),
+        }
+    }
+}
+
+/// How the `env` target reads input.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum InputMode {
+    /// A byte at a time, from `env.getc`.
+    Getc,
+
+    /// As much as the host has at once, from `env.read(ptr, len)`, into a
+    /// buffer in a page past the tape. `read` should return the number of
+    /// bytes it wrote, or zero at the end of input.
+    Buffered,
+}
+
+impl FromStr for InputMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<InputMode, String> {
+        match s {
+            "getc" => Ok(InputMode::Getc),
+            "buffered" => Ok(InputMode::Buffered),
+            _ => Err(format!("invalid input mode '{}'", s)),
+        }
+    }
+}
+
+/// An optional extra command.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Extension {
+    /// `#` calls the `env.dump` import with the address of the current cell,
+    /// so the host can print the tape around it.
+    Debug,
+
+    /// `~` makes the current cell's value the stream `.` writes to, which
+    /// starts as 1 for standard output. The `env` target writes through
+    /// `env.write(stream, byte)` rather than `putc`, and WASI passes the
+    /// stream to `fd_write` as the file descriptor.
+    Streams,
+}
+
+impl FromStr for Extension {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Extension, String> {
+        match s {
+            "debug" => Ok(Extension::Debug),
+            "streams" => Ok(Extension::Streams),
+            _ => Err(format!("invalid extension '{}'", s)),
+        }
+    }
+}
+
+/// A WebAssembly proposal the compiled module may use, which not every host
+/// supports.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Feature {
+    /// Bulk memory operations, so runs of clears can be a single
+    /// `memory.fill`.
+    BulkMemory,
+}
+
+impl FromStr for Feature {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Feature, String> {
+        match s {
+            "bulk-memory" => Ok(Feature::BulkMemory),
+            _ => Err(format!("invalid feature '{}'", s)),
+        }
+    }
+}
+
+/// Which optimizations to apply.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Optimizations {
+    /// Fold runs of `+`/`-` and `>`/`<` into single ops, and cancel those
+    /// which undo each other.
+    pub fold_runs: bool,
+
+    /// Lower clear, copy and multiply loops to straight-line code, and fold
+    /// clears followed by adds into a single store.
+    pub lower_loops: bool,
+
+    /// Drop loops straight after another loop, which can never run.
+    pub dead_loops: bool,
+
+    /// Lower `[>]` and `[<]` to tight scanning loops.
+    pub scan_loops: bool,
+
+    /// Keep the current cell in a local across straight-line code.
+    pub cache_cells: bool,
+
+    /// Fold moves within straight-line code into the addresses of the cells
+    /// which follow them.
+    pub defer_moves: bool,
+
+    /// Unroll simple loops which are known to run only a few times, such as
+    /// `++++[.>+<-]`.
+    pub unroll_loops: bool,
+
+    /// Run the start of the program at compile time, up to the first `,`,
+    /// and write what it would have written all at once. This needs a tape
+    /// size or a memory of the module's own, to keep the output past the
+    /// tape, and cells which wrap, and does nothing for programs compiled
+    /// with `compile_function`, whose tape might not start out zero.
+    pub precompute_output: bool,
+
+    /// Test whether to leave a loop with `i32.eqz` on the cell, rather than
+    /// comparing it with a zero constant.
+    pub eqz_loops: bool,
+}
+
+impl Optimizations {
+    /// The optimizations enabled at a given `-O` level:
+    ///
+    /// * 0 turns everything off, leaving one op per command;
+    /// * 1 folds runs, and tests loops with `i32.eqz`;
+    /// * 2 also lowers clear, copy and multiply loops, and drops loops which
+    ///   can never run;
+    /// * 3 and up also lower scan loops, cache cells, defer moves, unroll
+    ///   loops and precompute output.
+    pub fn level(level: u32) -> Optimizations {
+        Optimizations {
+            fold_runs: level >= 1,
+            lower_loops: level >= 2,
+            dead_loops: level >= 2,
+            scan_loops: level >= 3,
+            cache_cells: level >= 3,
+            defer_moves: level >= 3,
+            unroll_loops: level >= 3,
+            precompute_output: level >= 3,
+            eqz_loops: level >= 1,
+        }
+    }
+}
+
+impl Default for Optimizations {
+    fn default() -> Optimizations {
+        Optimizations::level(3)
+    }
+}
+
+/// Options controlling how a Brainfuck program is compiled.
+#[derive(Clone, Debug)]
+pub struct CompileOptions {
+    /// The minimum size of the tape in bytes, rounded up to whole pages when
+    /// declaring the memory. Zero leaves the sizing entirely to the host.
+    pub tape_size: u32,
+
+    /// The most the tape may grow to in bytes, rounded up to whole pages like
+    /// `tape_size`, so the host can cap it. Has to be at least `tape_size`.
+    /// `None` leaves the memory unbounded.
+    pub max_tape_size: Option<u32>,
+
+    /// The cell the pointer starts at, leaving the cells before it free for
+    /// the program to move back into. Has to be on the tape, if the tape has
+    /// a size.
+    pub start_offset: u32,
+
+    /// Define and export the memory holding the tape, rather than importing
+    /// it from the host.
+    pub self_contained_memory: bool,
+
+    /// Declare the memory with no minimum size, and have `main` grow it to
+    /// fit the tape before the program runs instead, trapping if it can't,
+    /// so that a host which provides too small a memory still gets a working
+    /// program. Can't be used with initial data or the interpreter, which
+    /// have to be in memory before `main` runs.
+    pub grow_memory: bool,
+
+    /// The host interface for input and output.
+    pub target: Target,
+
+    /// How the module runs the program. The interpreter doesn't support
+    /// profiling, debug information, batched output or a wrapping pointer,
+    /// and needs a tape size unless the module defines its own memory, since
+    /// the bytecode goes just past the tape.
+    pub backend: Backend,
+
+    /// Bytes to preload the start of the tape with, rather than zeros. They
+    /// go in a data segment, so the module has to define its own memory.
+    pub initial_data: Vec<u8>,
+
+    /// Write the output of consecutive `.` commands in a single call. The
+    /// staged output lives in a page past the end of the tape, and the `env`
+    /// target imports `env.puts(ptr, len)` to write it.
+    pub batch_output: bool,
+
+    /// How the `env` target reads input.
+    pub input_mode: InputMode,
+
+    /// The width of each cell on the tape.
+    pub cell_size: CellSize,
+
+    /// Treat 8- and 16-bit cells as signed, so `.` passes `putc` a value
+    /// from -128 to 127 for 8-bit cells, rather than 0 to 255. 32-bit cells
+    /// are passed as they are either way. Signed cells can only wrap.
+    pub signed_cells: bool,
+
+    /// Tell the engine every cell access is aligned to a byte, rather than
+    /// to the size of a cell. Alignment is only a hint, and cells are
+    /// naturally aligned unless the host puts the tape somewhere odd, but
+    /// some engines slow right down on misaligned accesses they were told
+    /// were aligned.
+    pub force_align_1: bool,
+
+    /// The behavior of `,` at the end of input.
+    pub eof: Eof,
+
+    /// The behavior of `+` and `-` at the limits of a cell. Only 8- and 16-bit
+    /// cells can saturate or trap.
+    pub overflow: Overflow,
+
+    /// The language the source is written in. Everything but the parsing
+    /// of the source is the same for every dialect.
+    pub dialect: Dialect,
+
+    /// Reject any byte that isn't a Brainfuck command or a line break,
+    /// rather than treating it as a comment. In Ook!, reject anything
+    /// between the tokens other than whitespace.
+    pub strict: bool,
+
+    /// Treat `warnings` about the source as errors.
+    pub deny_warnings: bool,
+
+    /// Reject programs with loops nested more than this deep, which some
+    /// runtimes can't handle. Zero means no limit.
+    pub max_nesting: u32,
+
+    /// Trap whenever the pointer leaves the tape. Needs a nonzero
+    /// `tape_size`.
+    pub bounds_check: bool,
+
+    /// Wrap the pointer around from one end of the tape to the other, rather
+    /// than leaving it. Needs a nonzero `tape_size`, and makes
+    /// `bounds_check` moot.
+    pub pointer_wrap: bool,
+
+    /// Check bounds as `bounds_check` does, and also keep track of the
+    /// furthest cell the program reaches, passing the number of cells up to
+    /// and including it to `high_water(cells)` when it finishes. Can't be
+    /// combined with `pointer_wrap`.
+    pub sanitize: bool,
+
+    /// Keep the tape in locals rather than memory, when the program's loops
+    /// all leave the pointer where they found it and it reaches no more than
+    /// `TAPE_LOCALS_MAX` cells, none of them off the tape. Locals are quicker
+    /// than memory, but this only pays off for small programs, and any other
+    /// program is compiled as if this weren't set. It has no effect with
+    /// bounds checking, pointer wrapping or initial data.
+    pub tape_in_locals: bool,
+
+    /// Trap when `.` writes anything but printable ASCII, newlines, tabs and
+    /// carriage returns, for programs which are meant to write text. Output
+    /// isn't precomputed then, and can't be batched.
+    pub strict_ascii_output: bool,
+
+    /// Call `trap(code)` with a `TrapCode` saying why, just before trapping
+    /// for bounds checks, `max_steps`, `Overflow::Trap`, `grow_memory` or
+    /// `strict_ascii_output`, so the host can tell the user what went wrong.
+    pub report_traps: bool,
+
+    /// Add a custom section, named by `DEBUG_SECTION`, mapping the code
+    /// generated for each command back to its offset in the source. This
+    /// adds a marker instruction pair ahead of every command's code, so it
+    /// makes the output quite a bit bigger.
+    pub debug_info: bool,
+
+    /// Treat everything from a `;` to the end of its line as a comment, even
+    /// in strict mode, so that commands in it aren't run.
+    pub line_comments: bool,
+
+    /// Add DWARF line information, in `.debug_info`, `.debug_abbrev` and
+    /// `.debug_line` sections, mapping the code generated for each command
+    /// to its line and column in the source, so debuggers can step through
+    /// it. Like `debug_info`, this means a marker ahead of every command's
+    /// code.
+    pub dwarf: bool,
+
+    /// The name of the source file, for debug information.
+    pub source_name: String,
+
+    /// Extra commands to recognize. Without them, their characters are
+    /// comments like any other.
+    pub extensions: Vec<Extension>,
+
+    /// The optimizations to apply.
+    pub optimizations: Optimizations,
+
+    /// Proposals beyond the WebAssembly MVP which the host supports, and the
+    /// module may use.
+    pub features: Vec<Feature>,
+
+    /// Make the program's function the module's start function as well as
+    /// exporting it, so the program runs as soon as the module is
+    /// instantiated. Hosts shouldn't call the export as well, or the program
+    /// runs twice.
+    pub start: bool,
+
+    /// The name the program's function is exported as.
+    pub export_name: String,
+
+    /// The module all of the host's imports, other than WASI's, come from.
+    pub import_module: String,
+
+    /// The field the tape's memory is imported as, unless it's
+    /// self-contained.
+    pub memory_import: String,
+
+    /// The field `.` is imported as, for the `env` target.
+    pub putc_import: String,
+
+    /// The field `,` is imported as, for the `env` target.
+    pub getc_import: String,
+
+    /// Count every op of each `Counter`'s kind as it runs, and export
+    /// `PROFILE_EXPORT` to read the counts back. This costs a global add for
+    /// every op run. Counts are of ops once optimized, so `+++` only counts
+    /// as one increment when runs are folded.
+    pub profile: bool,
+
+    /// The most loop iterations the program may run, counted across every
+    /// loop, before it traps. Zero means there's no limit. This costs a
+    /// check and a decrement every time round every loop. With a limit, the
+    /// module exports `STEPS_EXPORT` to read back how many steps it took.
+    pub max_steps: u64,
+
+    /// Add a producers section naming bf2wasm and its version, as well as
+    /// walrus, which tools like `wasm-objdump` show.
+    pub producers: bool,
+
+    /// Wrap the program in a block of its own in the main function. Nothing
+    /// breaks out of it, so leaving it out makes the function a little
+    /// smaller and flatter.
+    pub main_block: bool,
+
+    /// Pad the encoded module with a custom section so that its size is a
+    /// multiple of this many bytes, for embedding it somewhere aligned. Zero
+    /// leaves it as it is.
+    pub pad_to: u32,
+
+    /// Run the encoded module through a validator before returning it, so
+    /// that bugs in code generation are caught here rather than by the
+    /// host. On by default in debug builds.
+    pub validate_output: bool,
+}
+
+impl CompileOptions {
+    /// The minimum number of memory pages needed to hold the tape.
+    pub fn tape_pages(&self) -> u32 {
+        bytes_to_pages(self.tape_size)
+    }
+
+    /// The minimum number of memory pages the compiled module declares or
+    /// imports, including the page past the tape which WASI, batched output
+    /// and buffered input keep their buffers in.
+    ///
+    /// Nobody else is going to size a memory the module defines itself, and
+    /// the I/O page has to go somewhere, so the tape gets at least a page in
+    /// those cases even if no size was asked for.
+    pub fn memory_pages(&self) -> u32 {
+        self.with_io_page(self.tape_pages())
+    }
+
+    /// The most memory pages the compiled module lets its memory grow to, if
+    /// there's a limit, counted the same way as `memory_pages`.
+    pub fn max_memory_pages(&self) -> Option<u32> {
+        self.max_tape_size
+            .map(|max| self.with_io_page(bytes_to_pages(max)))
+    }
+
+    fn with_io_page(&self, tape_pages: u32) -> u32 {
+        if self.needs_io_page() {
+            tape_pages.max(1) + 1
+        } else if self.self_contained_memory {
+            tape_pages.max(1)
+        } else {
+            tape_pages
+        }
+    }
+
+    /// Whether the extra command `extension` is enabled.
+    pub fn has_extension(&self, extension: Extension) -> bool {
+        self.extensions.contains(&extension)
+    }
+
+    /// Whether the module traps when the pointer leaves the tape.
+    pub(crate) fn checks_bounds(&self) -> bool {
+        self.bounds_check || self.sanitize
+    }
+
+    /// Whether the module may use `feature`.
+    pub fn has_feature(&self, feature: Feature) -> bool {
+        self.features.contains(&feature)
+    }
+
+    pub(crate) fn needs_io_page(&self) -> bool {
+        self.target == Target::Wasi || self.batch_output || self.input_mode == InputMode::Buffered
+    }
+}
+
+impl Default for CompileOptions {
+    fn default() -> CompileOptions {
+        CompileOptions {
+            tape_size: 0,
+            max_tape_size: None,
+            start_offset: 0,
+            self_contained_memory: false,
+            grow_memory: false,
+            target: Target::Env,
+            backend: Backend::Compiled,
+            initial_data: Vec::new(),
+            batch_output: false,
+            input_mode: InputMode::Getc,
+            cell_size: CellSize::Bits8,
+            signed_cells: false,
+            force_align_1: false,
+            eof: Eof::Passthrough,
+            overflow: Overflow::Wrap,
+            dialect: Dialect::Brainfuck,
+            strict: false,
+            deny_warnings: false,
+            max_nesting: 0,
+            bounds_check: false,
+            pointer_wrap: false,
+            sanitize: false,
+            tape_in_locals: false,
+            strict_ascii_output: false,
+            report_traps: false,
+            debug_info: false,
+            line_comments: false,
+            dwarf: false,
+            source_name: String::from("program.bf"),
+            extensions: Vec::new(),
+            optimizations: Optimizations::default(),
+            features: Vec::new(),
+            start: false,
+            export_name: String::from("main"),
+            import_module: String::from("env"),
+            memory_import: String::from("memory"),
+            putc_import: String::from("putc"),
+            getc_import: String::from("getc"),
+            profile: false,
+            max_steps: 0,
+            producers: true,
+            main_block: true,
+            pad_to: 0,
+            validate_output: cfg!(debug_assertions),
+        }
+    }
+}
+
+/// The number of whole pages it takes to hold `bytes`.
+fn bytes_to_pages(bytes: u32) -> u32 {
+    let page = u64::from(PAGE_SIZE);
+    ((u64::from(bytes) + page - 1) / page) as u32
+}
@@ -0,0 +1,37 @@
+use crate::debug_info::push_custom_section;
+
+/// The name of the custom section `append_padding` pads modules with.
+pub const PADDING_SECTION: &str = "bf2wasm.padding";
+
+/// Pad `wasm` out to a multiple of `multiple` bytes with a custom section of
+/// zeros, which anything loading the module ignores. Modules which are
+/// already a multiple are left alone.
+///
+/// The section's header grows with its size, by a byte every seven bits, so
+/// the smallest section which works is found by trying each size in turn.
+/// Most of the time the first one to try works.
+pub(crate) fn append_padding(wasm: &mut Vec<u8>, multiple: u32) {
+    let multiple = multiple as usize;
+    if multiple == 0 || wasm.len() % multiple == 0 {
+        return;
+    }
+    let target = (wasm.len() / multiple + 1) * multiple;
+    let mut zeros = 0;
+    loop {
+        let mut section = Vec::new();
+        push_custom_section(&mut section, PADDING_SECTION, &vec![0; zeros]);
+        let padded = wasm.len() + section.len();
+        if padded % multiple == 0 {
+            wasm.extend(section);
+            return;
+        }
+        // Skip straight to the size which reaches the next multiple, unless
+        // the header grew past it.
+        let target = if padded < target {
+            target
+        } else {
+            (padded / multiple + 1) * multiple
+        };
+        zeros += target - padded;
+    }
+}
@@ -0,0 +1,101 @@
+use std::fmt::{self, Display};
+
+/// The byte order mark some editors start UTF-8 files with. It isn't part of
+/// the program, so columns on the first line are counted from after it.
+pub(crate) const BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// How many bytes of byte order mark `source` starts with.
+pub(crate) fn bom_len(source: &[u8]) -> usize {
+    if source.starts_with(BOM) {
+        BOM.len()
+    } else {
+        0
+    }
+}
+
+/// A location in Brainfuck source.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Position {
+    /// The byte offset from the start of the source.
+    pub offset: usize,
+
+    /// The 1-based line number.
+    pub line: usize,
+
+    /// The 1-based column number, counted in bytes. A byte order mark at the
+    /// start of the source doesn't count.
+    pub column: usize,
+}
+
+impl Position {
+    /// Find the line and column of the byte at `offset` in `source`.
+    pub fn locate(source: &[u8], offset: usize) -> Position {
+        let before = &source[..offset];
+        let line_start = before
+            .iter()
+            .rposition(|&byte| byte == b'\n')
+            .map_or(bom_len(before), |newline| newline + 1);
+        Position {
+            offset: offset,
+            line: before.iter().filter(|&&byte| byte == b'\n').count() + 1,
+            column: offset - line_start + 1,
+        }
+    }
+}
+
+/// Where each line of some source starts, for finding positions in it once
+/// the source itself is gone.
+#[derive(Clone, Debug)]
+pub(crate) struct Lines {
+    starts: Vec<usize>,
+    len: usize,
+    bom: usize,
+}
+
+impl Lines {
+    pub fn new() -> Lines {
+        Lines {
+            starts: vec![0],
+            len: 0,
+            bom: 0,
+        }
+    }
+
+    /// Take note of the lines in the next piece of the source.
+    pub fn feed(&mut self, bf: &[u8]) {
+        let len = self.len;
+        // The byte order mark may not all come in the first piece.
+        for (offset, &byte) in (len..).zip(bf) {
+            if offset != self.bom || BOM.get(offset) != Some(&byte) {
+                break;
+            }
+            self.bom += 1;
+            if self.bom == BOM.len() {
+                self.starts[0] = BOM.len();
+            }
+        }
+        let newlines = bf.iter().enumerate().filter(|&(_, &byte)| byte == b'\n');
+        self.starts.extend(newlines.map(|(i, _)| len + i + 1));
+        self.len += bf.len();
+    }
+
+    /// Find the line and column of the byte at `offset`.
+    pub fn locate(&self, offset: usize) -> Position {
+        let line = match self.starts.binary_search(&offset) {
+            Ok(line) => line,
+            // Only the byte order mark comes before the first line.
+            Err(next) => next.saturating_sub(1),
+        };
+        Position {
+            offset: offset,
+            line: line + 1,
+            column: offset.saturating_sub(self.starts[line]) + 1,
+        }
+    }
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
@@ -0,0 +1,67 @@
+use crate::ir::Op;
+
+/// The name of the function a module compiled with `profile` exports to
+/// read its counters, which takes a counter's index and returns its count.
+pub const PROFILE_EXPORT: &str = "profile";
+
+/// What one of a profiled module's counters counts.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Counter {
+    /// Ops which change cells: `+`, `-`, and the clears, copies and
+    /// multiplies loops are lowered to.
+    Arithmetic,
+
+    /// Ops which move the pointer.
+    Moves,
+
+    /// Ops which write output.
+    Outputs,
+
+    /// Ops which read input.
+    Inputs,
+
+    /// Times round any loop, including scans.
+    Iterations,
+}
+
+impl Counter {
+    /// Every counter, in order of index.
+    pub const ALL: [Counter; 5] = [
+        Counter::Arithmetic,
+        Counter::Moves,
+        Counter::Outputs,
+        Counter::Inputs,
+        Counter::Iterations,
+    ];
+
+    /// The index `PROFILE_EXPORT` takes to read this counter.
+    pub fn index(self) -> i32 {
+        self as i32
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Counter::Arithmetic => "arithmetic",
+            Counter::Moves => "moves",
+            Counter::Outputs => "outputs",
+            Counter::Inputs => "inputs",
+            Counter::Iterations => "iterations",
+        }
+    }
+}
+
+/// The counter each time `op` runs counts towards, if any.
+pub(crate) fn counter_for(op: Op) -> Option<Counter> {
+    match op {
+        Op::Add(_)
+        | Op::Clear
+        | Op::Set(_)
+        | Op::Fill(_)
+        | Op::MulAdd { .. }
+        | Op::MulSet { .. } => Some(Counter::Arithmetic),
+        Op::Move(_) => Some(Counter::Moves),
+        Op::Output | Op::Stage(_) | Op::Print(_) => Some(Counter::Outputs),
+        Op::Input => Some(Counter::Inputs),
+        Op::Open | Op::Close | Op::Scan(_) | Op::Flush(_) | Op::Dump | Op::Stream => None,
+    }
+}
@@ -0,0 +1,88 @@
+use crate::{Error, ErrorKind};
+
+pub(crate) const CUSTOM_SECTION: u8 = 0;
+pub(crate) const IMPORT_SECTION: u8 = 2;
+pub(crate) const START_SECTION: u8 = 8;
+pub(crate) const CODE_SECTION: u8 = 10;
+
+/// A cursor over part of an encoded module. Running off the end of the
+/// input, or into anything we didn't expect, means we've misunderstood what
+/// walrus emitted, so every failure is an internal error.
+pub(crate) struct Reader<'a> {
+    pub wasm: &'a [u8],
+    pub position: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Start reading the sections of a module, just past its header.
+    pub fn new(wasm: &[u8]) -> Reader {
+        Reader {
+            wasm: wasm,
+            position: 8,
+        }
+    }
+
+    pub fn byte(&mut self) -> Result<u8, Error> {
+        let byte = *self.wasm.get(self.position).ok_or(ErrorKind::Ice)?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    pub fn skip(&mut self, len: usize) -> Result<(), Error> {
+        if self.wasm.len() - self.position < len {
+            Err(ErrorKind::Ice)?;
+        }
+        self.position += len;
+        Ok(())
+    }
+
+    pub fn uleb(&mut self) -> Result<u64, Error> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            if shift < 64 {
+                value |= u64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    pub fn sleb(&mut self) -> Result<i64, Error> {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            if shift < 64 {
+                value |= i64::from(byte & 0x7f) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    pub fn len(&mut self) -> Result<usize, Error> {
+        Ok(self.uleb()? as usize)
+    }
+}
+
+/// Whether an encoded module has a section with the given id.
+pub(crate) fn has_section(wasm: &[u8], id: u8) -> Result<bool, Error> {
+    let mut reader = Reader::new(wasm);
+    while reader.position < wasm.len() {
+        if reader.byte()? == id {
+            return Ok(true);
+        }
+        let len = reader.len()?;
+        reader.skip(len)?;
+    }
+    Ok(false)
+}
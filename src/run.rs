@@ -0,0 +1,471 @@
+use crate::error::ResultExt;
+use crate::reader::{self, START_SECTION};
+use crate::{CompileOptions, Counter, Error, ErrorKind, TrapCode, PROFILE_EXPORT, STEPS_EXPORT};
+use std::cell::RefCell;
+use std::fmt::{self, Display};
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+use wasmi::memory_units::Pages;
+use wasmi::{
+    Externals, FuncInstance, FuncRef, HostError, ImportsBuilder, MemoryDescriptor, MemoryInstance,
+    MemoryRef, ModuleImportResolver, ModuleInstance, RuntimeArgs, RuntimeValue, Signature, Trap,
+    TrapKind,
+};
+
+const PUTC: usize = 0;
+const GETC: usize = 1;
+const FD_WRITE: usize = 2;
+const FD_READ: usize = 3;
+const PUTS: usize = 4;
+const DUMP: usize = 5;
+const READ: usize = 6;
+const HIGH_WATER: usize = 7;
+const TRAP: usize = 8;
+const WRITE: usize = 9;
+
+const WASI_ESUCCESS: i32 = 0;
+const WASI_EBADF: i32 = 8;
+
+#[derive(Debug)]
+struct IoTrap(io::Error);
+
+impl Display for IoTrap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl HostError for IoTrap {}
+
+/// A trap the program reported through `trap`.
+#[derive(Debug)]
+struct ProgramTrap(i32);
+
+impl Display for ProgramTrap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match TrapCode::from_code(self.0) {
+            Some(code) => f.write_str(code.description()),
+            None => write!(f, "the program trapped with code {}", self.0),
+        }
+    }
+}
+
+impl HostError for ProgramTrap {}
+
+/// The error for `error` while running a module, which says why it trapped
+/// if the program told us.
+fn runtime_error(error: wasmi::Error) -> Error {
+    if let wasmi::Error::Trap(trap) = &error {
+        if let TrapKind::Host(host_error) = trap.kind() {
+            if let Some(reported) = host_error.downcast_ref::<ProgramTrap>() {
+                return Error::new(ErrorKind::Runtime, reported.to_string());
+            }
+        }
+    }
+    Error::new(ErrorKind::Runtime, error)
+}
+
+fn io_trap(error: io::Error) -> Trap {
+    Trap::new(TrapKind::Host(Box::new(IoTrap(error))))
+}
+
+fn unknown_import(module_name: &str, field_name: &str) -> wasmi::Error {
+    wasmi::Error::Instantiation(format!("unknown import {}.{}", module_name, field_name))
+}
+
+/// Resolves the host's own imports of a compiled module, keeping hold of
+/// the memory it provides so that `puts` can read from it.
+struct Env<'a> {
+    options: &'a CompileOptions,
+    memory: RefCell<Option<MemoryRef>>,
+}
+
+impl<'a> ModuleImportResolver for Env<'a> {
+    fn resolve_func(
+        &self,
+        field_name: &str,
+        signature: &Signature,
+    ) -> Result<FuncRef, wasmi::Error> {
+        let index = match field_name {
+            _ if field_name == self.options.putc_import => PUTC,
+            _ if field_name == self.options.getc_import => GETC,
+            "puts" => PUTS,
+            "dump" => DUMP,
+            "read" => READ,
+            "high_water" => HIGH_WATER,
+            "trap" => TRAP,
+            "write" => WRITE,
+            _ => return Err(unknown_import(&self.options.import_module, field_name)),
+        };
+        Ok(FuncInstance::alloc_host(signature.clone(), index))
+    }
+
+    fn resolve_memory(
+        &self,
+        field_name: &str,
+        descriptor: &MemoryDescriptor,
+    ) -> Result<MemoryRef, wasmi::Error> {
+        if field_name != self.options.memory_import {
+            return Err(unknown_import(&self.options.import_module, field_name));
+        }
+        // Like index.js, give a module which leaves sizing to the host a
+        // single page.
+        let initial = descriptor.initial().max(1);
+        let maximum = descriptor.maximum().map(|maximum| Pages(maximum as usize));
+        let memory = MemoryInstance::alloc(Pages(initial as usize), maximum)?;
+        *self.memory.borrow_mut() = Some(memory.clone());
+        Ok(memory)
+    }
+}
+
+/// Resolves the handful of WASI imports the WASI target uses.
+struct Wasi;
+
+impl ModuleImportResolver for Wasi {
+    fn resolve_func(
+        &self,
+        field_name: &str,
+        signature: &Signature,
+    ) -> Result<FuncRef, wasmi::Error> {
+        let index = match field_name {
+            "fd_write" => FD_WRITE,
+            "fd_read" => FD_READ,
+            _ => return Err(unknown_import("wasi_snapshot_preview1", field_name)),
+        };
+        Ok(FuncInstance::alloc_host(signature.clone(), index))
+    }
+}
+
+/// Implements the host functions in terms of a reader and a writer.
+struct Host<R, W> {
+    input: R,
+    output: W,
+
+    /// The module's memory, which `puts` and the WASI functions read their
+    /// arguments from and write their results to.
+    memory: Option<MemoryRef>,
+
+    /// How many cells of the tape the program reached, once a sanitized
+    /// program says.
+    cells: Option<u32>,
+}
+
+fn memory_trap(_: wasmi::Error) -> Trap {
+    Trap::new(TrapKind::MemoryAccessOutOfBounds)
+}
+
+impl<R: Read, W: Write> Host<R, W> {
+    /// Print the bytes of the tape around `address` to standard error, with
+    /// the byte at `address` in brackets.
+    fn dump(&self, address: u32) -> io::Result<()> {
+        let start = address.saturating_sub(8);
+        let mut line = format!("dump: {:#x}:", address);
+        if let Some(memory) = &self.memory {
+            for byte_address in start..address.saturating_add(8) {
+                let byte: u8 = match memory.get_value(byte_address) {
+                    Ok(byte) => byte,
+                    Err(_) => break,
+                };
+                if byte_address == address {
+                    line += &format!(" [{:02x}]", byte);
+                } else {
+                    line += &format!(" {:02x}", byte);
+                }
+            }
+        }
+        writeln!(io::stderr(), "{}", line)
+    }
+
+    /// Write `bytes` to stream `fd`, which is the output for 1 and standard
+    /// error for 2. Returns whether there's such a stream.
+    fn write_stream(&mut self, fd: i32, bytes: &[u8]) -> Result<bool, Trap> {
+        match fd {
+            1 => self.output.write_all(bytes).map_err(io_trap)?,
+            2 => io::stderr().write_all(bytes).map_err(io_trap)?,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Call `transfer` with each buffer of an iovec array, returning the
+    /// total number of bytes transferred to `transfer`'s results.
+    fn fd_transfer<F>(&mut self, args: RuntimeArgs, mut transfer: F) -> Result<i32, Trap>
+    where
+        F: FnMut(&mut Self, &MemoryRef, u32, u32) -> Result<usize, Trap>,
+    {
+        let iovs: u32 = args.nth_checked(1)?;
+        let iovs_len: u32 = args.nth_checked(2)?;
+        let nbytes: u32 = args.nth_checked(3)?;
+        let memory = match self.memory.clone() {
+            Some(memory) => memory,
+            None => return Ok(WASI_EBADF),
+        };
+        let mut total = 0;
+        for i in 0..iovs_len {
+            let iov = iovs + i * 8;
+            let buf: u32 = memory.get_value(iov).map_err(memory_trap)?;
+            let len: u32 = memory.get_value(iov + 4).map_err(memory_trap)?;
+            let transferred = transfer(self, &memory, buf, len)?;
+            total += transferred;
+            if transferred < len as usize {
+                break;
+            }
+        }
+        memory
+            .set_value(nbytes, total as u32)
+            .map_err(memory_trap)?;
+        Ok(WASI_ESUCCESS)
+    }
+}
+
+impl<R: Read, W: Write> Externals for Host<R, W> {
+    fn invoke_index(
+        &mut self,
+        index: usize,
+        args: RuntimeArgs,
+    ) -> Result<Option<RuntimeValue>, Trap> {
+        match index {
+            PUTC => {
+                let value: i32 = args.nth_checked(0)?;
+                self.output.write_all(&[value as u8]).map_err(io_trap)?;
+                Ok(None)
+            }
+            GETC => {
+                let mut byte = [0];
+                let value = match self.input.read(&mut byte).map_err(io_trap)? {
+                    0 => -1,
+                    _ => i32::from(byte[0]),
+                };
+                Ok(Some(RuntimeValue::I32(value)))
+            }
+            PUTS => {
+                let buf: u32 = args.nth_checked(0)?;
+                let len: u32 = args.nth_checked(1)?;
+                let memory = self
+                    .memory
+                    .clone()
+                    .ok_or_else(|| Trap::new(TrapKind::MemoryAccessOutOfBounds))?;
+                let bytes = memory.get(buf, len as usize).map_err(memory_trap)?;
+                self.output.write_all(&bytes).map_err(io_trap)?;
+                Ok(None)
+            }
+            READ => {
+                let buf: u32 = args.nth_checked(0)?;
+                let len: u32 = args.nth_checked(1)?;
+                let memory = self
+                    .memory
+                    .clone()
+                    .ok_or_else(|| Trap::new(TrapKind::MemoryAccessOutOfBounds))?;
+                let mut bytes = vec![0; len as usize];
+                let n = self.input.read(&mut bytes).map_err(io_trap)?;
+                memory.set(buf, &bytes[..n]).map_err(memory_trap)?;
+                Ok(Some(RuntimeValue::I32(n as i32)))
+            }
+            TRAP => {
+                let code: i32 = args.nth_checked(0)?;
+                Err(Trap::new(TrapKind::Host(Box::new(ProgramTrap(code)))))
+            }
+            HIGH_WATER => {
+                self.cells = Some(args.nth_checked(0)?);
+                Ok(None)
+            }
+            DUMP => {
+                let address: u32 = args.nth_checked(0)?;
+                self.dump(address).map_err(io_trap)?;
+                Ok(None)
+            }
+            WRITE => {
+                // Output to a stream the host doesn't have goes nowhere.
+                let fd: i32 = args.nth_checked(0)?;
+                let value: i32 = args.nth_checked(1)?;
+                self.write_stream(fd, &[value as u8])?;
+                Ok(None)
+            }
+            FD_WRITE => {
+                let fd: i32 = args.nth_checked(0)?;
+                if fd != 1 && fd != 2 {
+                    return Ok(Some(RuntimeValue::I32(WASI_EBADF)));
+                }
+                let errno = self.fd_transfer(args, |host, memory, buf, len| {
+                    let bytes = memory.get(buf, len as usize).map_err(memory_trap)?;
+                    host.write_stream(fd, &bytes)?;
+                    Ok(bytes.len())
+                })?;
+                Ok(Some(RuntimeValue::I32(errno)))
+            }
+            FD_READ => {
+                let fd: i32 = args.nth_checked(0)?;
+                if fd != 0 {
+                    return Ok(Some(RuntimeValue::I32(WASI_EBADF)));
+                }
+                let errno = self.fd_transfer(args, |host, memory, buf, len| {
+                    let mut bytes = vec![0; len as usize];
+                    let n = host.input.read(&mut bytes).map_err(io_trap)?;
+                    memory.set(buf, &bytes[..n]).map_err(memory_trap)?;
+                    Ok(n)
+                })?;
+                Ok(Some(RuntimeValue::I32(errno)))
+            }
+            _ => unreachable!("no host function {}", index),
+        }
+    }
+}
+
+/// How a run of a compiled module went.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct RunStats {
+    /// How long the program took to run, not counting loading the module.
+    pub duration: Duration,
+
+    /// The number of steps the program took, if it was compiled with
+    /// `max_steps`.
+    pub steps: Option<u64>,
+
+    /// How many cells of the tape the program reached, if it was compiled
+    /// with `sanitize`.
+    pub cells: Option<u32>,
+
+    /// The count for each `Counter`, in the order of `Counter::ALL`, if the
+    /// program was compiled with `profile`, or else nothing.
+    pub profile: Vec<(Counter, u32)>,
+}
+
+impl RunStats {
+    /// Describe the run as a single line of JSON, for tools to read.
+    pub fn to_json(&self) -> String {
+        let steps = match self.steps {
+            Some(steps) => steps.to_string(),
+            None => String::from("null"),
+        };
+        format!(
+            "{{\"seconds\":{},\"steps\":{}}}",
+            self.duration.as_secs_f64(),
+            steps
+        )
+    }
+}
+
+impl Display for RunStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.duration.as_secs_f64();
+        write!(f, "ran in {:.3} s", seconds)?;
+        if let Some(steps) = self.steps {
+            write!(f, ", taking {} steps", steps)?;
+            if seconds > 0.0 {
+                write!(f, " at {:.0} steps per second", steps as f64 / seconds)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Run a compiled module's `main`, or its start function if it has one,
+/// connecting its input to `input` and its output to `output`, whichever
+/// target it was compiled for.
+pub fn run<R: Read, W: Write>(wasm: &[u8], input: R, output: W) -> Result<RunStats, Error> {
+    run_with_options(wasm, &CompileOptions::default(), input, output)
+}
+
+/// Like `run`, for a module compiled with `options` which change what it
+/// imports and exports.
+pub fn run_with_options<R: Read, W: Write>(
+    wasm: &[u8],
+    options: &CompileOptions,
+    input: R,
+    output: W,
+) -> Result<RunStats, Error> {
+    let module = wasmi::Module::from_buffer(wasm).context(ErrorKind::Ice)?;
+    let env = Env {
+        options: options,
+        memory: RefCell::new(None),
+    };
+    let imports = ImportsBuilder::new()
+        .with_resolver(&options.import_module, &env)
+        .with_resolver("wasi_snapshot_preview1", &Wasi);
+    let mut host = Host {
+        input: input,
+        output: output,
+        memory: None,
+        cells: None,
+    };
+    let instance = ModuleInstance::new(&module, &imports).context(ErrorKind::Runtime)?;
+    host.memory = instance
+        .not_started_instance()
+        .export_by_name("memory")
+        .and_then(|export| export.as_memory().cloned())
+        .or_else(|| env.memory.borrow().clone());
+    let started = Instant::now();
+    let instance = instance
+        .run_start(&mut host)
+        .map_err(|trap| runtime_error(trap.into()))?;
+    // A module with a start function has already run.
+    if !reader::has_section(wasm, START_SECTION)? {
+        instance
+            .invoke_export(&options.export_name, &[], &mut host)
+            .map_err(runtime_error)?;
+    }
+    host.output.flush().context(ErrorKind::Io)?;
+    let duration = started.elapsed();
+    let steps = if options.max_steps > 0 {
+        let steps = instance
+            .invoke_export(STEPS_EXPORT, &[], &mut host)
+            .context(ErrorKind::Runtime)?;
+        match steps {
+            Some(RuntimeValue::I64(steps)) => Some(steps as u64),
+            _ => Err(ErrorKind::Ice)?,
+        }
+    } else {
+        None
+    };
+    let mut profile = Vec::new();
+    if options.profile {
+        for &counter in &Counter::ALL {
+            let args = [RuntimeValue::I32(counter.index())];
+            let count = instance
+                .invoke_export(PROFILE_EXPORT, &args, &mut host)
+                .context(ErrorKind::Runtime)?;
+            match count {
+                Some(RuntimeValue::I32(count)) => profile.push((counter, count as u32)),
+                _ => Err(ErrorKind::Ice)?,
+            }
+        }
+    }
+    Ok(RunStats {
+        duration: duration,
+        steps: steps,
+        cells: host.cells,
+        profile: profile,
+    })
+}
+
+/// Check that a run's `actual` output is the `expected` output, as from a
+/// source's `;; expect-output:` comments. The error says where they first
+/// differ.
+pub fn check_output(expected: &[u8], actual: &[u8]) -> Result<(), Error> {
+    if expected == actual {
+        return Ok(());
+    }
+    let at = expected
+        .iter()
+        .zip(actual)
+        .take_while(|(expected, actual)| expected == actual)
+        .count();
+    // A little of each either side of the difference is enough to see it.
+    let snippet = |bytes: &[u8]| {
+        let end = bytes.len().min(at + 16);
+        bytes[at.saturating_sub(16)..end]
+            .iter()
+            .flat_map(|&byte| std::ascii::escape_default(byte))
+            .map(char::from)
+            .collect::<String>()
+    };
+    Err(Error::new(
+        ErrorKind::Runtime,
+        format!(
+            "output differs from expect-output at byte {}: expected \"{}\", got \"{}\"",
+            at,
+            snippet(expected),
+            snippet(actual)
+        ),
+    ))
+}
@@ -0,0 +1,160 @@
+use std::fmt::{self, Display};
+
+/// Counts describing a compiled program.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct Stats {
+    /// The number of `+` commands.
+    pub increments: usize,
+
+    /// The number of `-` commands.
+    pub decrements: usize,
+
+    /// The number of `>` commands.
+    pub rights: usize,
+
+    /// The number of `<` commands.
+    pub lefts: usize,
+
+    /// The number of `.` commands.
+    pub outputs: usize,
+
+    /// The number of `,` commands.
+    pub inputs: usize,
+
+    /// The number of loops, before any are lowered to straight-line code.
+    pub loops: usize,
+
+    /// The deepest nesting of loops.
+    pub max_depth: usize,
+
+    /// The number of ops left to generate code for once optimization is
+    /// finished.
+    pub ops: usize,
+
+    /// The size of the encoded module in bytes.
+    pub wasm_size: usize,
+
+    /// What the optimizer did to the program.
+    pub optimized: Optimized,
+}
+
+/// How many times each optimization fired.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct Optimized {
+    /// The number of `+`, `-`, `>` and `<` commands folded into the run
+    /// before them.
+    pub folded_commands: usize,
+
+    /// The number of runs dropped because they cancelled out, as in `+-`.
+    pub cancelled_runs: usize,
+
+    /// The number of loops lowered to a clear, such as `[-]`.
+    pub clear_loops: usize,
+
+    /// The number of loops lowered to copies or multiplies, such as
+    /// `[->++<]`.
+    pub multiply_loops: usize,
+
+    /// The number of loops lowered to scans, such as `[>]`.
+    pub scan_loops: usize,
+
+    /// The number of loops dropped because they could never run.
+    pub dead_loops: usize,
+
+    /// The number of clears followed by copies into them which became
+    /// assignments.
+    pub assignments: usize,
+
+    /// The number of adds folded into the clear or set before them.
+    pub sets: usize,
+
+    /// The number of loops unrolled because they run a known, small number
+    /// of times.
+    pub unrolled_loops: usize,
+
+    /// The number of runs of clears which became fills.
+    pub fills: usize,
+
+    /// The number of batches of output written at once.
+    pub batches: usize,
+
+    /// The number of ops at the start of the program run at compile time,
+    /// and replaced by writing their output all at once.
+    pub precomputed_ops: usize,
+}
+
+impl Optimized {
+    /// Describe the counts as a single line of JSON, for tools to read.
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"folded_commands\":{},\"cancelled_runs\":{},\"clear_loops\":{},\"multiply_loops\":{},\"scan_loops\":{},\"dead_loops\":{},\"assignments\":{},\"sets\":{},\"unrolled_loops\":{},\"fills\":{},\"batches\":{},\"precomputed_ops\":{}}}",
+            self.folded_commands,
+            self.cancelled_runs,
+            self.clear_loops,
+            self.multiply_loops,
+            self.scan_loops,
+            self.dead_loops,
+            self.assignments,
+            self.sets,
+            self.unrolled_loops,
+            self.fills,
+            self.batches,
+            self.precomputed_ops
+        )
+    }
+}
+
+impl Display for Optimized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "folded {} commands into runs, cancelled {} runs, lowered {} clear loops, {} copy or multiply loops and {} scan loops, dropped {} dead loops, made {} assignments, {} sets and {} fills, unrolled {} loops, batched output {} times, and precomputed {} ops",
+            self.folded_commands,
+            self.cancelled_runs,
+            self.clear_loops,
+            self.multiply_loops,
+            self.scan_loops,
+            self.dead_loops,
+            self.assignments,
+            self.sets,
+            self.fills,
+            self.unrolled_loops,
+            self.batches,
+            self.precomputed_ops
+        )
+    }
+}
+
+impl Stats {
+    /// Describe the statistics as a single line of JSON, for tools to read.
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"increments\":{},\"decrements\":{},\"rights\":{},\"lefts\":{},\"outputs\":{},\"inputs\":{},\"loops\":{},\"max_depth\":{},\"ops\":{},\"wasm_size\":{}}}",
+            self.increments,
+            self.decrements,
+            self.rights,
+            self.lefts,
+            self.outputs,
+            self.inputs,
+            self.loops,
+            self.max_depth,
+            self.ops,
+            self.wasm_size
+        )
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "+: {}", self.increments)?;
+        writeln!(f, "-: {}", self.decrements)?;
+        writeln!(f, ">: {}", self.rights)?;
+        writeln!(f, "<: {}", self.lefts)?;
+        writeln!(f, ".: {}", self.outputs)?;
+        writeln!(f, ",: {}", self.inputs)?;
+        writeln!(f, "loops: {}", self.loops)?;
+        writeln!(f, "max loop depth: {}", self.max_depth)?;
+        writeln!(f, "ops after optimization: {}", self.ops)?;
+        write!(f, "wasm size: {} bytes", self.wasm_size)
+    }
+}
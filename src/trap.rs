@@ -0,0 +1,49 @@
+/// Why a compiled program trapped, as passed to the `trap` import of a
+/// module compiled with `report_traps`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum TrapCode {
+    /// The pointer went past the end of the tape.
+    AboveTape = 1,
+
+    /// The pointer went before the start of the tape.
+    BelowTape = 2,
+
+    /// The program used up its `max_steps`.
+    StepLimit = 3,
+
+    /// A cell went out of range with `Overflow::Trap`.
+    CellOverflow = 4,
+
+    /// The memory couldn't grow to fit the tape with `grow_memory`.
+    OutOfMemory = 5,
+
+    /// `.` wrote something other than text with `strict_ascii_output`.
+    NotAscii = 6,
+}
+
+impl TrapCode {
+    /// The trap code passed to `trap` as `code`, if it is one.
+    pub fn from_code(code: i32) -> Option<TrapCode> {
+        match code {
+            1 => Some(TrapCode::AboveTape),
+            2 => Some(TrapCode::BelowTape),
+            3 => Some(TrapCode::StepLimit),
+            4 => Some(TrapCode::CellOverflow),
+            5 => Some(TrapCode::OutOfMemory),
+            6 => Some(TrapCode::NotAscii),
+            _ => None,
+        }
+    }
+
+    /// What happened, for people to read.
+    pub fn description(self) -> &'static str {
+        match self {
+            TrapCode::AboveTape => "the pointer went past the end of the tape",
+            TrapCode::BelowTape => "the pointer went before the start of the tape",
+            TrapCode::StepLimit => "the program ran out of steps",
+            TrapCode::CellOverflow => "a cell overflowed",
+            TrapCode::OutOfMemory => "the memory couldn't grow to fit the tape",
+            TrapCode::NotAscii => "the program wrote a byte which isn't ASCII text",
+        }
+    }
+}
@@ -0,0 +1,478 @@
+use bf2wasm::walrus;
+use bf2wasm::{
+    check_output, compile, compile_function, compile_programs, compile_to_module,
+    compile_with_stats, expected_output, manifest, run_with_options, to_wat, warnings, Backend,
+    CellSize, CompileOptions, Counter, DiagnosticKind, Eof, ErrorKind, Extension, Optimizations,
+    Position, Target,
+};
+
+#[test]
+fn deeply_nested_loops() {
+    let mut bf = vec![b'['; 50_000];
+    bf.extend(vec![b']'; 50_000]);
+    assert!(compile(&bf, &CompileOptions::default()).is_ok());
+}
+
+#[test]
+fn balanced_runs_compile_to_nothing() {
+    let mut wrapped = vec![b'+'; 256];
+    wrapped.push(b'>');
+    wrapped.extend(vec![b'-'; 256]);
+    wrapped.push(b'<');
+    for bf in &[&b"++++----"[..], b"+-><-+", b">+<>-<", &wrapped[..]] {
+        let (_, stats) = compile_with_stats(bf, &CompileOptions::default()).unwrap();
+        assert_eq!(stats.ops, 0, "{}", String::from_utf8_lossy(bf));
+    }
+}
+
+#[test]
+fn deterministic_output() {
+    let bf = include_bytes!("../hello.bf");
+    let options = [
+        CompileOptions::default(),
+        CompileOptions {
+            target: Target::Wasi,
+            batch_output: true,
+            debug_info: true,
+            extensions: vec![Extension::Debug],
+            ..CompileOptions::default()
+        },
+    ];
+    for options in &options {
+        assert_eq!(compile(bf, options).unwrap(), compile(bf, options).unwrap());
+    }
+}
+
+#[test]
+fn start_offset() {
+    let options = CompileOptions {
+        tape_size: 65536,
+        bounds_check: true,
+        start_offset: 2,
+        ..CompileOptions::default()
+    };
+    let wasm = compile(b"<<+.", &options).unwrap();
+    let mut output = Vec::new();
+    run_with_options(&wasm, &options, &b""[..], &mut output).unwrap();
+    assert_eq!(output, [1]);
+
+    let options = CompileOptions {
+        start_offset: 65536,
+        ..options
+    };
+    assert_eq!(
+        compile(b"", &options).unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+}
+
+#[test]
+fn functions_share_a_module() {
+    let options = CompileOptions::default();
+    let mut module = walrus::Module::with_config(walrus::ModuleConfig::new());
+    let first = compile_function(b"+.", &mut module, &options).unwrap();
+    let second = compile_function(b"++.", &mut module, &options).unwrap();
+    module.exports.add("main", first);
+    module.exports.add("second", second);
+    let putcs = module.imports.iter().filter(|import| import.name == "putc");
+    assert_eq!(putcs.count(), 1);
+
+    let wasm = module.emit_wasm().unwrap();
+    let mut output = Vec::new();
+    run_with_options(&wasm, &options, &b""[..], &mut output).unwrap();
+    assert_eq!(output, [1]);
+}
+
+/// The programs only share the tape: their pointers are locals, not
+/// globals, so one can't move another's.
+#[test]
+fn compile_programs_exports_each() {
+    let options = CompileOptions {
+        export_name: String::from("second"),
+        ..CompileOptions::default()
+    };
+    let programs: [(&str, &[u8]); 2] = [("first", b">+"), ("second", b"+.")];
+    let wasm = compile_programs(&programs, &options).unwrap();
+    let manifest = manifest(&wasm).unwrap();
+    assert!(manifest.contains("{\"name\":\"first\",\"kind\":\"function\""));
+    assert!(!to_wat(&wasm).unwrap().contains("global"));
+    let mut output = Vec::new();
+    run_with_options(&wasm, &options, &b""[..], &mut output).unwrap();
+    assert_eq!(output, [1]);
+
+    let programs: [(&str, &[u8]); 2] = [("main", b"+"), ("main", b"-")];
+    let error = compile_programs(&programs, &options).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+}
+
+#[test]
+fn max_tape_size() {
+    let options = CompileOptions {
+        tape_size: 65536,
+        max_tape_size: Some(100_000),
+        ..CompileOptions::default()
+    };
+    let module = compile_to_module(b"+.", &options).unwrap();
+    let memory = module.memories.iter().next().unwrap();
+    assert_eq!((memory.initial, memory.maximum), (1, Some(2)));
+
+    let options = CompileOptions {
+        max_tape_size: Some(0),
+        ..options
+    };
+    assert_eq!(
+        compile(b"", &options).unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+}
+
+/// The host gives the module a page, whatever the tape size, so a module
+/// which grew by the wrong amount would either trap on the tape's last cell
+/// or reach the one past it.
+#[test]
+fn grow_memory() {
+    let options = CompileOptions {
+        tape_size: 3 * 65536,
+        start_offset: 3 * 65536 - 1,
+        grow_memory: true,
+        ..CompileOptions::default()
+    };
+    let module = compile_to_module(b"+.", &options).unwrap();
+    let memory = module.memories.iter().next().unwrap();
+    assert_eq!(memory.initial, 0);
+
+    let wasm = compile(b"+.", &options).unwrap();
+    let mut output = Vec::new();
+    run_with_options(&wasm, &options, &b""[..], &mut output).unwrap();
+    assert_eq!(output, [1]);
+
+    let wasm = compile(b">+.", &options).unwrap();
+    let error = run_with_options(&wasm, &options, &b""[..], Vec::new()).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::Runtime);
+}
+
+#[test]
+fn steps() {
+    let options = CompileOptions {
+        max_steps: 100,
+        ..CompileOptions::default()
+    };
+    let wasm = compile(b"+++[>++[-.]<-]", &options).unwrap();
+    let mut output = Vec::new();
+    let stats = run_with_options(&wasm, &options, &b""[..], &mut output).unwrap();
+    assert_eq!(stats.steps, Some(9));
+
+    // A loop which could be unrolled still counts its steps.
+    for level in 0..=3 {
+        let options = CompileOptions {
+            optimizations: Optimizations::level(level),
+            ..options.clone()
+        };
+        let wasm = compile(b"++++[>.+<-]", &options).unwrap();
+        let stats = run_with_options(&wasm, &options, &b""[..], Vec::new()).unwrap();
+        assert_eq!(stats.steps, Some(4), "-O{}", level);
+    }
+}
+
+/// Write an `A` to standard error and then a `B` to the output, which is all
+/// the runner keeps.
+#[test]
+fn streams() {
+    let bf = format!(">++~<{}.>-~<+.", "+".repeat(65));
+    for &target in &[Target::Env, Target::Wasi] {
+        let options = CompileOptions {
+            target: target,
+            extensions: vec![Extension::Streams],
+            ..CompileOptions::default()
+        };
+        let wasm = compile(bf.as_bytes(), &options).unwrap();
+        let mut output = Vec::new();
+        run_with_options(&wasm, &options, &b""[..], &mut output).unwrap();
+        assert_eq!(output, b"B");
+    }
+}
+
+#[test]
+fn expect_output() {
+    let bf = b"++++++++[>++++++++<-]>+.+. ;; expect-output: AB\\n\n; expect-output: C\n";
+    let options = CompileOptions {
+        line_comments: true,
+        ..CompileOptions::default()
+    };
+    assert_eq!(expected_output(bf, &CompileOptions::default()), None);
+    let expected = expected_output(bf, &options).unwrap();
+    assert_eq!(expected, b"AB\n");
+
+    let wasm = compile(bf, &options).unwrap();
+    let mut output = Vec::new();
+    run_with_options(&wasm, &options, &b""[..], &mut output).unwrap();
+    check_output(b"AB", &output).unwrap();
+    let error = check_output(&expected, &output).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::Runtime);
+    assert!(error.message().contains("at byte 2"), "{}", error.message());
+}
+
+#[test]
+fn option_header() {
+    let bf = b";; bf2wasm: --cell-size 16  --eof=zero\r\n+.";
+    let (args, blanked) = bf2wasm::option_header(bf).unwrap();
+    assert_eq!(args, ["--cell-size", "16", "--eof=zero"]);
+    assert_eq!(blanked.len(), bf.len());
+    assert!(blanked.ends_with(b"\n+."));
+    compile(&blanked, &CompileOptions::default()).unwrap();
+
+    assert_eq!(
+        bf2wasm::option_header(b"+. ;; bf2wasm: --cell-size 16"),
+        None
+    );
+}
+
+#[test]
+fn manifest_describes_the_module() {
+    let wasm = compile(b",.", &CompileOptions::default()).unwrap();
+    let manifest = manifest(&wasm).unwrap();
+    for expected in &[
+        "{\"module\":\"env\",\"name\":\"putc\",\"kind\":\"function\",\"params\":[\"i32\"],\"results\":[]}",
+        "{\"module\":\"env\",\"name\":\"getc\",\"kind\":\"function\",\"params\":[],\"results\":[\"i32\"]}",
+        "{\"name\":\"main\",\"kind\":\"function\",\"params\":[],\"results\":[]}",
+        "{\"name\":\"tape_size\",\"kind\":\"function\",\"params\":[],\"results\":[\"i32\"]}",
+    ] {
+        assert!(manifest.contains(expected), "{} isn't in {}", expected, manifest);
+    }
+}
+
+#[test]
+fn padding() {
+    for &pad_to in &[1, 7, 64, 65536] {
+        let options = CompileOptions {
+            pad_to: pad_to,
+            ..CompileOptions::default()
+        };
+        let wasm = compile(b"+++.", &options).unwrap();
+        assert_eq!(wasm.len() % pad_to as usize, 0);
+        let mut output = Vec::new();
+        run_with_options(&wasm, &options, &b""[..], &mut output).unwrap();
+        assert_eq!(output, [3]);
+    }
+}
+
+#[test]
+fn loop_names() {
+    let options = CompileOptions {
+        debug_info: true,
+        ..CompileOptions::default()
+    };
+    let wasm = compile(b"\n,[.,]", &options).unwrap();
+    for name in &[&b"loop@2:2"[..], b"break@2:2"] {
+        assert!(wasm.windows(name.len()).any(|window| window == *name));
+    }
+}
+
+/// Cells are naturally aligned, which `wasm2wat` leaves unsaid.
+#[test]
+fn cell_alignment() {
+    for &cell_size in &[CellSize::Bits16, CellSize::Bits32] {
+        let options = CompileOptions {
+            cell_size: cell_size,
+            ..CompileOptions::default()
+        };
+        let wat = to_wat(&compile(b"+>+<[->+<]>.", &options).unwrap()).unwrap();
+        assert!(!wat.contains("align="), "{}", wat);
+
+        let options = CompileOptions {
+            force_align_1: true,
+            ..options
+        };
+        let wat = to_wat(&compile(b"+>+<[->+<]>.", &options).unwrap()).unwrap();
+        assert!(wat.contains("align=1"), "{}", wat);
+    }
+}
+
+/// From -O1 up, loops test their cell with `i32.eqz` rather than comparing
+/// it with zero, and still run the same.
+#[test]
+fn eqz_loops() {
+    let bf = b"++[>+<-]>.";
+    for &(level, eqz) in &[(0, false), (1, true)] {
+        let options = CompileOptions {
+            optimizations: Optimizations::level(level),
+            ..CompileOptions::default()
+        };
+        let wasm = compile(bf, &options).unwrap();
+        let wat = to_wat(&wasm).unwrap();
+        assert_eq!(wat.contains("i32.eqz"), eqz, "-O{}", level);
+        assert_eq!(
+            wat.lines().any(|line| line.trim() == "i32.eq"),
+            !eqz,
+            "-O{}",
+            level
+        );
+        let mut output = Vec::new();
+        run_with_options(&wasm, &options, &b""[..], &mut output).unwrap();
+        assert_eq!(output, [2]);
+    }
+}
+
+#[test]
+fn error_position() {
+    let error = compile(b"+\n+]", &CompileOptions::default()).unwrap_err();
+    let position = Position {
+        offset: 3,
+        line: 2,
+        column: 2,
+    };
+    assert_eq!(error.position(), Some(position));
+    assert_eq!(error.to_string(), "Invalid input at line 2, column 2");
+}
+
+#[test]
+fn max_nesting() {
+    let options = CompileOptions {
+        max_nesting: 2,
+        ..CompileOptions::default()
+    };
+    assert!(compile(b"[[]][[]]", &options).is_ok());
+    let error = compile(b"[[]\n[[[]]]]", &options).unwrap_err();
+    let diagnostics = error.diagnostics();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].kind, DiagnosticKind::TooDeep(2));
+    assert_eq!(diagnostics[0].position.line, 2);
+    assert_eq!(diagnostics[0].position.column, 2);
+}
+
+/// A byte order mark and Windows line endings are neither commands nor
+/// mistakes in strict mode, and don't throw the columns off.
+#[test]
+fn bom_and_crlf() {
+    let options = CompileOptions {
+        strict: true,
+        ..CompileOptions::default()
+    };
+    compile(b"\xef\xbb\xbf+\r\n+\r\n", &options).unwrap();
+    let error = compile(b"\xef\xbb\xbf+]\r\n", &options).unwrap_err();
+    let position = Position {
+        offset: 4,
+        line: 1,
+        column: 2,
+    };
+    assert_eq!(error.position(), Some(position));
+    let error = compile(b"+\r+", &options).unwrap_err();
+    assert_eq!(error.position().map(|position| position.offset), Some(1));
+}
+
+#[test]
+fn infinite_loops() {
+    let options = CompileOptions::default();
+    let found = warnings(b"[]+[>+<.]\n+[-]+[>[-]<]", &options);
+    let offsets: Vec<usize> = found
+        .iter()
+        .map(|warning| warning.position.offset)
+        .collect();
+    assert_eq!(offsets, [3]);
+    assert_eq!(found[0].kind, DiagnosticKind::InfiniteLoop);
+    compile(b"+[]", &options).unwrap();
+
+    let options = CompileOptions {
+        deny_warnings: true,
+        ..options
+    };
+    let error = compile(b"+[]", &options).unwrap_err();
+    assert_eq!(error.diagnostics()[0].kind, DiagnosticKind::InfiniteLoop);
+}
+
+#[test]
+fn sanitize_reports_cells() {
+    let options = CompileOptions {
+        tape_size: 65536,
+        sanitize: true,
+        ..CompileOptions::default()
+    };
+    let wasm = compile(b"+>+>+<.", &options).unwrap();
+    let run_stats = run_with_options(&wasm, &options, &b""[..], Vec::new()).unwrap();
+    assert_eq!(run_stats.cells, Some(3));
+}
+
+#[test]
+fn profile_counts() {
+    let options = CompileOptions {
+        profile: true,
+        ..CompileOptions::default()
+    };
+    let wasm = compile(b"+.>+..", &options).unwrap();
+    let run_stats = run_with_options(&wasm, &options, &b""[..], Vec::new()).unwrap();
+    assert_eq!(run_stats.profile.len(), Counter::ALL.len());
+    assert!(run_stats.profile.contains(&(Counter::Outputs, 3)));
+}
+
+#[test]
+fn reported_traps() {
+    let options = CompileOptions {
+        tape_size: 65536,
+        bounds_check: true,
+        report_traps: true,
+        ..CompileOptions::default()
+    };
+    let wasm = compile(b"<+", &options).unwrap();
+    let error = run_with_options(&wasm, &options, &b""[..], Vec::new()).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::Runtime);
+    assert!(error.message().contains("before the start of the tape"));
+}
+
+/// A multiply loop which never runs never reaches its target, so it mustn't
+/// trap for being off the tape once it's lowered either.
+#[test]
+fn lowered_loop_bounds() {
+    for &backend in &[Backend::Compiled, Backend::Interpreter] {
+        for &level in &[2, 3] {
+            let options = CompileOptions {
+                tape_size: 65536,
+                bounds_check: true,
+                eof: Eof::Zero,
+                backend: backend,
+                optimizations: Optimizations::level(level),
+                ..CompileOptions::default()
+            };
+            let wasm = compile(b",[<+>-]", &options).unwrap();
+            run_with_options(&wasm, &options, &b""[..], Vec::new()).unwrap();
+            let error = run_with_options(&wasm, &options, &b"\x01"[..], Vec::new()).unwrap_err();
+            assert_eq!(
+                error.kind(),
+                ErrorKind::Runtime,
+                "{:?} -O{}",
+                backend,
+                level
+            );
+        }
+    }
+}
+
+#[test]
+fn strict_ascii_output() {
+    for &backend in &[Backend::Compiled, Backend::Interpreter] {
+        let options = CompileOptions {
+            strict_ascii_output: true,
+            report_traps: true,
+            backend: backend,
+            tape_size: 65536,
+            ..CompileOptions::default()
+        };
+        let wasm = compile(include_bytes!("../hello.bf"), &options).unwrap();
+        let mut output = Vec::new();
+        run_with_options(&wasm, &options, &b""[..], &mut output).unwrap();
+        assert_eq!(output, b"Hello World!\n");
+
+        let wasm = compile(b"++++++++[>++++++++<-]>+.[-]+.", &options).unwrap();
+        let mut output = Vec::new();
+        let error = run_with_options(&wasm, &options, &b""[..], &mut output).unwrap_err();
+        assert_eq!(output, b"A", "{:?}", backend);
+        assert!(error.message().contains("isn't ASCII text"));
+    }
+}
+
+#[test]
+fn module_emits_like_compile() {
+    let bf = include_bytes!("../hello.bf");
+    let options = CompileOptions::default();
+    let mut module = compile_to_module(bf, &options).unwrap();
+    assert_eq!(module.emit_wasm().unwrap(), compile(bf, &options).unwrap());
+}
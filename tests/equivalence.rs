@@ -0,0 +1,279 @@
+use bf2wasm::{
+    compile, run_with_options, Backend, CellSize, CompileOptions, Eof, Optimizations, Overflow,
+    Target,
+};
+
+const HELLO: &str = include_str!("../hello.bf");
+const CAT: &str = ",[.,]";
+const FIBONACCI: &str = "++++++++++>>+<<[>.[->>+<<]>[-<+>>+<]>[-<+>]<<<-]";
+const MULTIPLY: &str = "++++[>+++<-]>.";
+const OFFSET_MOVE: &str = "+++[->>>+<<<]>>>.";
+const COUNT_UP: &str = "--[+>++>>-<<<]>.>>.";
+const ASSIGN: &str = "++>+++<[->+<]+++++>[-]<[->+>>++<<<]>.>>.";
+const ASSIGN_ZERO: &str = ">,<.>[-]<[->+<]>.";
+const UNROLL: &str = "++++[>.+<-]>.";
+const SCAN: &str = ">+>+>+[<]>.";
+const WRAP: &str = "-.+.";
+
+/// A straightforward interpreter to compare the compiled code with. Cells
+/// wrap, which is all the corpus needs.
+fn interpret(bf: &[u8], options: &CompileOptions, mut input: &[u8]) -> Vec<u8> {
+    let mut targets = vec![0; bf.len()];
+    let mut open = Vec::new();
+    for (i, &byte) in bf.iter().enumerate() {
+        match byte {
+            b'[' => open.push(i),
+            b']' => {
+                let start = open.pop().unwrap();
+                targets[start] = i;
+                targets[i] = start;
+            }
+            _ => {}
+        }
+    }
+
+    let max = options.cell_size.max_value();
+    let mut tape = vec![0u32; 64 * 1024];
+    let mut pointer = 0;
+    let mut output = Vec::new();
+    let mut pc = 0;
+    while pc < bf.len() {
+        match bf[pc] {
+            b'+' => tape[pointer] = tape[pointer].wrapping_add(1) & max,
+            b'-' => tape[pointer] = tape[pointer].wrapping_sub(1) & max,
+            b'>' => pointer += 1,
+            b'<' => pointer -= 1,
+            b'.' => output.push(tape[pointer] as u8),
+            b',' => match input.split_first() {
+                Some((&byte, rest)) => {
+                    tape[pointer] = u32::from(byte);
+                    input = rest;
+                }
+                None => match options.eof {
+                    Eof::Passthrough | Eof::MinusOne => tape[pointer] = max,
+                    Eof::Zero => tape[pointer] = 0,
+                    Eof::Unchanged => {}
+                },
+            },
+            b'[' if tape[pointer] == 0 => pc = targets[pc],
+            b']' if tape[pointer] != 0 => pc = targets[pc],
+            _ => {}
+        }
+        pc += 1;
+    }
+    output
+}
+
+/// Check that `bf` does the same compiled at every optimization level as it
+/// does in the interpreter.
+fn assert_equivalent(bf: &str, input: &[u8], options: CompileOptions) {
+    for level in 0..=3 {
+        let options = CompileOptions {
+            optimizations: Optimizations::level(level),
+            ..options.clone()
+        };
+        let expected = interpret(bf.as_bytes(), &options, input);
+        let wasm = compile(bf.as_bytes(), &options).unwrap();
+        let mut output = Vec::new();
+        run_with_options(&wasm, &options, input, &mut output).unwrap();
+        assert_eq!(output, expected, "{:?} at -O{}", bf, level);
+    }
+}
+
+#[test]
+fn hello_world() {
+    assert_equivalent(HELLO, b"", CompileOptions::default());
+}
+
+#[test]
+fn cat() {
+    let options = CompileOptions {
+        eof: Eof::Zero,
+        ..CompileOptions::default()
+    };
+    assert_equivalent(CAT, b"Hello, cat!\n", options);
+}
+
+#[test]
+fn fibonacci() {
+    assert_equivalent(FIBONACCI, b"", CompileOptions::default());
+}
+
+#[test]
+fn multiply() {
+    assert_equivalent(MULTIPLY, b"", CompileOptions::default());
+}
+
+#[test]
+fn offset_move() {
+    assert_equivalent(OFFSET_MOVE, b"", CompileOptions::default());
+}
+
+#[test]
+fn count_up() {
+    assert_equivalent(COUNT_UP, b"", CompileOptions::default());
+    let options = CompileOptions {
+        cell_size: CellSize::Bits16,
+        ..CompileOptions::default()
+    };
+    assert_equivalent(COUNT_UP, b"", options);
+}
+
+#[test]
+fn assign() {
+    assert_equivalent(ASSIGN, b"", CompileOptions::default());
+    assert_equivalent(ASSIGN_ZERO, b"\x05", CompileOptions::default());
+}
+
+#[test]
+fn unroll() {
+    assert_equivalent(UNROLL, b"", CompileOptions::default());
+    let options = CompileOptions {
+        overflow: Overflow::Trap,
+        ..CompileOptions::default()
+    };
+    assert_equivalent(UNROLL, b"", options);
+}
+
+#[test]
+fn scan() {
+    assert_equivalent(SCAN, b"", CompileOptions::default());
+}
+
+#[test]
+fn wrapping() {
+    assert_equivalent(WRAP, b"", CompileOptions::default());
+    let options = CompileOptions {
+        cell_size: CellSize::Bits16,
+        ..CompileOptions::default()
+    };
+    assert_equivalent(WRAP, b"", options);
+}
+
+/// Overflow a cell several times over while it's cached in a local, and
+/// look at it along the way and once it's back in memory.
+#[test]
+fn cached_overflow() {
+    let bf = format!(
+        "{}.{}.>{}<.",
+        "+".repeat(200),
+        "+".repeat(100),
+        "-".repeat(300)
+    );
+    assert_equivalent(&bf, b"", CompileOptions::default());
+    let options = CompileOptions {
+        cell_size: CellSize::Bits16,
+        ..CompileOptions::default()
+    };
+    let bf = format!("{}.{}.><.", "-".repeat(70000), "+".repeat(70000));
+    assert_equivalent(&bf, b"", options);
+}
+
+#[test]
+fn interpreter() {
+    for &bf in &[
+        HELLO,
+        FIBONACCI,
+        MULTIPLY,
+        OFFSET_MOVE,
+        ASSIGN,
+        UNROLL,
+        SCAN,
+        WRAP,
+    ] {
+        let options = CompileOptions {
+            backend: Backend::Interpreter,
+            tape_size: 65536,
+            ..CompileOptions::default()
+        };
+        assert_equivalent(bf, b"", options);
+    }
+    let options = CompileOptions {
+        backend: Backend::Interpreter,
+        self_contained_memory: true,
+        eof: Eof::Zero,
+        ..CompileOptions::default()
+    };
+    assert_equivalent(CAT, b"Hello, cat!\n", options);
+}
+
+#[test]
+fn no_main_block() {
+    let options = CompileOptions {
+        main_block: false,
+        eof: Eof::Zero,
+        ..CompileOptions::default()
+    };
+    assert_equivalent(HELLO, b"", options.clone());
+    assert_equivalent(CAT, b"Hello, cat!\n", options);
+}
+
+/// With a tape size, everything up to the first `,` runs at compile time.
+#[test]
+fn precompute_output() {
+    for &bf in &[
+        HELLO,
+        FIBONACCI,
+        MULTIPLY,
+        OFFSET_MOVE,
+        COUNT_UP,
+        ASSIGN,
+        SCAN,
+        WRAP,
+        "++++++++[>++++++++<-]>+.+.[-]<,+.",
+    ] {
+        for &target in &[Target::Env, Target::Wasi] {
+            let options = CompileOptions {
+                tape_size: 65536,
+                target: target,
+                eof: Eof::Zero,
+                ..CompileOptions::default()
+            };
+            assert_equivalent(bf, b"A", options);
+        }
+        let options = CompileOptions {
+            tape_size: 65536,
+            cell_size: CellSize::Bits16,
+            eof: Eof::Zero,
+            ..CompileOptions::default()
+        };
+        assert_equivalent(bf, b"A", options);
+    }
+}
+
+#[test]
+fn end_of_input() {
+    for &eof in &[Eof::Passthrough, Eof::Zero, Eof::MinusOne, Eof::Unchanged] {
+        let options = CompileOptions {
+            eof: eof,
+            ..CompileOptions::default()
+        };
+        assert_equivalent("+,.", b"", options);
+    }
+}
+
+#[test]
+fn tape_in_locals() {
+    for &bf in &[
+        HELLO,
+        FIBONACCI,
+        MULTIPLY,
+        OFFSET_MOVE,
+        COUNT_UP,
+        ASSIGN,
+        WRAP,
+    ] {
+        let options = CompileOptions {
+            tape_in_locals: true,
+            ..CompileOptions::default()
+        };
+        assert_equivalent(bf, b"", options);
+    }
+    let options = CompileOptions {
+        tape_in_locals: true,
+        eof: Eof::MinusOne,
+        cell_size: CellSize::Bits16,
+        ..CompileOptions::default()
+    };
+    assert_equivalent(",.,.", b"A", options);
+}
@@ -0,0 +1,23 @@
+use bf2wasm::{try_compile, CompileOptions, ErrorKind, TRY_COMPILE_MAX_DEPTH};
+use proptest::collection::vec;
+use proptest::prelude::*;
+
+proptest! {
+    #[test]
+    fn random_bytes(bf in vec(any::<u8>(), 0..512)) {
+        let _ = try_compile(&bf, &CompileOptions::default());
+    }
+
+    #[test]
+    fn random_commands(bf in "[-+<>.,\\[\\]]{0,512}") {
+        let _ = try_compile(bf.as_bytes(), &CompileOptions::default());
+    }
+}
+
+#[test]
+fn too_deeply_nested() {
+    let mut bf = vec![b'['; TRY_COMPILE_MAX_DEPTH + 1];
+    bf.extend(vec![b']'; TRY_COMPILE_MAX_DEPTH + 1]);
+    let error = try_compile(&bf, &CompileOptions::default()).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+}